@@ -0,0 +1,1490 @@
+use chrono::{SecondsFormat, Utc};
+use quicli::prelude::*;
+use regex::Regex;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+#[cfg(windows)]
+use std::os::windows::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use diff::{changed_lines, Change};
+use display::{color_enabled, color_enabled_on, paint, Color};
+use error::RetryError;
+use loop_manager::LoopManager;
+use options::RetryOptions;
+use process_group::{self, ProcessGroup};
+use signals;
+
+/// How often a running attempt is checked against its timeout, and for
+/// interruption
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How the command finished
+#[derive(Debug)]
+pub enum Outcome {
+    /// The command ran to completion
+    Exited(ExitStatus),
+    /// The command was killed for exceeding the attempt timeout
+    TimedOut(ExitStatus),
+    /// The command was killed because we were interrupted
+    Interrupted(ExitStatus),
+    /// The command printed a line matching --until-line, and was stopped
+    Matched(ExitStatus),
+}
+
+/// The result of running the command once
+#[derive(Debug)]
+pub struct Attempt {
+    pub outcome: Outcome,
+    /// Up to --max-capture bytes of stdout, if it was piped, followed by a
+    /// marker if there was more
+    pub stdout: Option<Vec<u8>>,
+    /// Up to --max-capture bytes of stderr, if it was piped, likewise
+    pub stderr: Option<Vec<u8>>,
+}
+
+impl Attempt {
+    /// An attempt which finished with `status`, without any captured output
+    pub fn exited(status: ExitStatus) -> Attempt {
+        Attempt {
+            outcome: Outcome::Exited(status),
+            stdout: None,
+            stderr: None,
+        }
+    }
+
+    pub fn status(&self) -> ExitStatus {
+        match self.outcome {
+            Outcome::Exited(status)
+            | Outcome::TimedOut(status)
+            | Outcome::Interrupted(status)
+            | Outcome::Matched(status) => status,
+        }
+    }
+
+    /// The exit code, if the command ran to completion and wasn't killed
+    pub fn code(&self) -> Option<i32> {
+        match self.outcome {
+            Outcome::Exited(status) => status.code(),
+            Outcome::TimedOut(_) | Outcome::Interrupted(_) | Outcome::Matched(_) => None,
+        }
+    }
+
+    /// The signal which killed the command, if it wasn't killed by us
+    #[cfg(unix)]
+    pub fn signal(&self) -> Option<i32> {
+        match self.outcome {
+            Outcome::Exited(status) => status.signal(),
+            Outcome::TimedOut(_) | Outcome::Interrupted(_) | Outcome::Matched(_) => None,
+        }
+    }
+
+    /// The signal which killed the command, which is never known outside Unix
+    #[cfg(not(unix))]
+    pub fn signal(&self) -> Option<i32> {
+        None
+    }
+}
+
+/// Something which can be attempted over and over
+pub trait Task {
+    /// Make an attempt, with `loop_manager` describing the retrying so far
+    fn attempt(&mut self, loop_manager: &LoopManager) -> Result<Attempt>;
+
+    /// Make an attempt, as `attempt` does, also saying whether it passed if
+    /// the task is made up of several, each of whose attempts is judged by
+    /// `passes`
+    fn attempt_judged(
+        &mut self,
+        loop_manager: &LoopManager,
+        _passes: &dyn Fn(&Attempt) -> bool,
+    ) -> Result<(Attempt, Option<bool>)> {
+        Ok((self.attempt(loop_manager)?, None))
+    }
+
+    /// Whether attempts run a command, so that their exit code is one of the
+    /// conditions for success
+    fn runs_command(&self) -> bool {
+        true
+    }
+}
+
+impl<F> Task for F
+where
+    F: FnMut(&LoopManager) -> Result<Attempt>,
+{
+    fn attempt(&mut self, loop_manager: &LoopManager) -> Result<Attempt> {
+        self(loop_manager)
+    }
+}
+
+/// Where captured output is passed through to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Echo {
+    /// Nowhere
+    Off,
+    /// The same stream it was read from
+    Inherit,
+    /// Our stderr, whichever stream it was read from
+    Stderr,
+}
+
+impl Echo {
+    fn stdout(self) -> Option<Box<dyn Write + Send>> {
+        match self {
+            Echo::Off => None,
+            Echo::Inherit => Some(Box::new(io::stdout())),
+            Echo::Stderr => Some(Box::new(io::stderr())),
+        }
+    }
+
+    fn stderr(self) -> Option<Box<dyn Write + Send>> {
+        match self {
+            Echo::Off => None,
+            Echo::Inherit | Echo::Stderr => Some(Box::new(io::stderr())),
+        }
+    }
+}
+
+/// A command which runs `script` through $SHELL, or sh if that isn't set
+pub(crate) fn shell_command(script: &str) -> Command {
+    let shell = env::var("SHELL").unwrap_or_else(|_| String::from("sh"));
+    let mut command = Command::new(shell);
+    command.arg("-c").arg(script);
+    command
+}
+
+/// The captured stdout and stderr of an attempt
+pub(crate) type CapturedOutput = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// A command run as a `Task`, as configured by `RetryOptions`. Without a
+/// command every attempt succeeds, leaving conditions such as
+/// --until-file-exists to decide when to stop.
+#[derive(Debug)]
+pub struct CommandTask {
+    command: Option<Command>,
+    cwd: Option<PathBuf>,
+    timeout: Option<Duration>,
+    kill_grace: Option<Duration>,
+    echo: Echo,
+    capture: Capture,
+    stdout_piped: bool,
+    input: Option<Vec<u8>>,
+    inject_env: bool,
+    tee: Option<PathBuf>,
+    echo_command: bool,
+    env_clear: bool,
+    /// Whether to point out that PATH was cleared, the first time the
+    /// command or a program it runs isn't found
+    path_hint: bool,
+    retry_spawn_errors: bool,
+    dedup_output: bool,
+    /// Whether only the lines which changed are shown, with --diff-output
+    diff_output: bool,
+    no_color: bool,
+    hold_output: bool,
+    /// Whether the output of attempts which succeed, or fail, is hidden, with
+    /// --quiet-success and --quiet-failure
+    quiet_success: bool,
+    quiet_failure: bool,
+    success_codes: Vec<i32>,
+    /// The stdout and stderr of the last attempt, with --dedup-output
+    previous_output: Option<CapturedOutput>,
+}
+
+impl CommandTask {
+    pub fn new(cmd: &[String], options: &RetryOptions) -> CommandTask {
+        let command = if cmd.is_empty() {
+            None
+        } else if options.shell {
+            Some(shell_command(&cmd.join(" ")))
+        } else {
+            let (cmd, cmd_args) = cmd.split_at(1);
+            let mut command = Command::new(&cmd[0]);
+            command.args(cmd_args);
+            Some(command)
+        };
+        CommandTask::with_command(command, options)
+    }
+
+    /// A task which runs `script` through $SHELL, or sh if that isn't set
+    pub fn script(script: &str, options: &RetryOptions) -> CommandTask {
+        CommandTask::with_command(Some(shell_command(script)), options)
+    }
+
+    fn with_command(command: Option<Command>, options: &RetryOptions) -> CommandTask {
+        CommandTask {
+            command: command.map(|command| CommandTask::configure(command, options)),
+            cwd: options.cwd.clone(),
+            timeout: options.attempt_timeout.map(|t| t.duration()),
+            kill_grace: options.kill_grace.map(|g| g.duration()),
+            echo: if options.quiet {
+                Echo::Off
+            } else {
+                Echo::Inherit
+            },
+            capture: Capture {
+                limit: usize::try_from(options.max_capture).unwrap_or(usize::MAX),
+                timestamps: options.timestamps,
+                tail: None,
+                label: None,
+                idle_timeout: options.idle_timeout.map(|t| t.duration()),
+                until_line: options.until_line.clone(),
+            },
+            stdout_piped: CommandTask::pipes_stdout(options),
+            input: None,
+            inject_env: !options.no_inject_env,
+            tee: options.tee.clone(),
+            echo_command: options.echo,
+            env_clear: options.env_clear || !options.env_keep.is_empty(),
+            path_hint: CommandTask::clears_path(options),
+            retry_spawn_errors: options.retry_spawn_errors,
+            dedup_output: options.dedup_output || options.diff_output,
+            diff_output: options.diff_output,
+            no_color: options.no_color,
+            hold_output: options.no_inherit_stdout_on_retry,
+            quiet_success: options.quiet_success,
+            quiet_failure: options.quiet_failure,
+            success_codes: options.success_codes(),
+            previous_output: None,
+        }
+    }
+
+    /// Whether anything needs to see the command's stdout
+    fn pipes_stdout(options: &RetryOptions) -> bool {
+        options.until_stdout_matches.is_some()
+            || options.until_stdout_not_matches.is_some()
+            || options.backoff_from_stdout.is_some()
+            || options.tee.is_some()
+            || options.dedup_output
+            || options.diff_output
+            || options.no_inherit_stdout_on_retry
+            || options.quiet_success
+            || options.quiet_failure
+            || options.stable_for.is_some()
+            || options.adaptive_poll
+            || options.show_last_output
+            || options.timestamps
+            || options.tail_on_failure.is_some()
+            || options.idle_timeout.is_some()
+            || options.until_line.is_some()
+    }
+
+    /// Whether the command's environment has no PATH
+    fn clears_path(options: &RetryOptions) -> bool {
+        let sets_path = options.env.iter().any(|(k, _)| k == "PATH")
+            || options
+                .env_file
+                .as_ref()
+                .is_some_and(|file| file.vars.iter().any(|(k, _)| k == "PATH"));
+        (options.env_clear || !options.env_keep.is_empty())
+            && !options.env_keep.iter().any(|k| k == "PATH")
+            && !sets_path
+    }
+
+    fn configure(mut command: Command, options: &RetryOptions) -> Command {
+        if let Some(ref dir) = options.cwd {
+            command.current_dir(dir);
+        }
+        if options.env_clear || !options.env_keep.is_empty() {
+            command.env_clear();
+        }
+        for key in &options.env_keep {
+            if let Some(value) = env::var_os(key) {
+                command.env(key, value);
+            }
+        }
+        if let Some(ref file) = options.env_file {
+            command.envs(file.vars.iter().cloned());
+        }
+        command.envs(options.env.iter().cloned());
+        if let Some(n) = options.nice {
+            set_nice(&mut command, n);
+        }
+        if let Some(bytes) = options.max_memory {
+            limit_memory(&mut command, bytes);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if options.die_with_parent {
+                die_with_parent(&mut command);
+            }
+        }
+        if options.quiet {
+            command.stdout(Stdio::null()).stderr(Stdio::null());
+        }
+        if CommandTask::pipes_stdout(options) {
+            command.stdout(Stdio::piped());
+        }
+        if options.until_stderr_matches.is_some()
+            || options.until_stderr_empty
+            || options.until_stderr_nonempty
+            || options.tee.is_some()
+            || options.dedup_output
+            || options.diff_output
+            || options.no_inherit_stdout_on_retry
+            || options.quiet_success
+            || options.quiet_failure
+            || options.stable_for.is_some()
+            || options.adaptive_poll
+            || options.show_last_output
+            || options.timestamps
+            || options.tail_on_failure.is_some()
+            || options.idle_timeout.is_some()
+        {
+            command.stderr(Stdio::piped());
+        }
+        command
+    }
+
+    /// Feed `input` to the command's stdin on every attempt, instead of it
+    /// inheriting ours
+    pub fn feed_stdin(&mut self, input: Vec<u8>) {
+        if let Some(ref mut command) = self.command {
+            command.stdin(Stdio::piped());
+        }
+        self.input = Some(input);
+    }
+
+    /// Start each line of the command's output with `label`, in brackets,
+    /// passing it on a line at a time
+    pub fn label_output(&mut self, label: &str) {
+        if let Some(ref mut command) = self.command {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        self.stdout_piped = true;
+        self.capture.label = Some(label.to_string());
+    }
+
+    /// Send the command's stdout to our stderr, keeping our stdout free for
+    /// our own output
+    pub fn reserve_stdout(&mut self) {
+        if self.echo == Echo::Off {
+            return;
+        }
+        self.echo = Echo::Stderr;
+        if let Some(ref mut command) = self.command {
+            if !self.stdout_piped {
+                command.stdout(io::stderr());
+            }
+        }
+    }
+}
+
+impl Task for CommandTask {
+    fn attempt(&mut self, loop_manager: &LoopManager) -> Result<Attempt> {
+        let command = match self.command {
+            Some(ref mut command) => command,
+            None => return Ok(Attempt::exited(ExitStatus::default())),
+        };
+        if let Some(ref dir) = self.cwd {
+            if !dir.is_dir() {
+                bail!("Working directory {} no longer exists", dir.display());
+            }
+        }
+        if self.inject_env {
+            command
+                .env(
+                    "RETRY_ITERATION",
+                    (loop_manager.iteration() + 1).to_string(),
+                )
+                .env(
+                    "RETRY_ELAPSED_MS",
+                    loop_manager.elapsed()?.as_millis().to_string(),
+                );
+        }
+        if self.echo_command {
+            eprintln!("+ {}", command_line(command, self.env_clear));
+        }
+        let tee = match self.tee {
+            Some(ref path) => Some(open_tee(path, loop_manager.iteration() + 1)?),
+            None => None,
+        };
+        // Output is held back until it can be compared with the last, or it
+        // is known whether the attempt succeeded
+        let held = self.quiet_success || self.quiet_failure;
+        let echo = if self.dedup_output || self.hold_output || held {
+            Echo::Off
+        } else {
+            self.echo
+        };
+        let result = run_attempt(
+            command,
+            self.input.as_deref(),
+            self.timeout,
+            self.kill_grace,
+            echo,
+            Capture {
+                tail: loop_manager.tail.clone(),
+                ..self.capture.clone()
+            },
+            tee.as_ref(),
+        );
+        let not_found = match result {
+            Ok(ref attempt) => attempt.code() == Some(127),
+            Err(ref e) => matches!(e.downcast_ref(), Some(RetryError::NotFound(_))),
+        };
+        if not_found && self.path_hint {
+            eprintln!("hint: PATH isn't passed on to the command; keep it with --env-keep PATH");
+            self.path_hint = false;
+        }
+        let attempt = match result {
+            Err(e) if self.retry_spawn_errors => match e.downcast::<RetryError>() {
+                Ok(error) => {
+                    // In place of the output the command would have given
+                    if self.echo != Echo::Off {
+                        eprintln!("{}", error);
+                    }
+                    Attempt::exited(exit_status(error.exit_code()))
+                }
+                Err(e) => return Err(e),
+            },
+            result => result?,
+        };
+        let succeeded = attempt
+            .code()
+            .is_some_and(|rc| self.success_codes.contains(&rc));
+        let hidden = if succeeded {
+            self.quiet_success
+        } else {
+            self.quiet_failure
+        };
+        if self.dedup_output && !hidden {
+            self.show_output(&attempt)?;
+        } else if held && !hidden {
+            self.replay_output(&attempt)?;
+        }
+        Ok(attempt)
+    }
+
+    fn runs_command(&self) -> bool {
+        self.command.is_some()
+    }
+}
+
+impl CommandTask {
+    /// Pass on the output of `attempt`, unless it is the same as last time,
+    /// or only the lines which changed with --diff-output
+    fn show_output(&mut self, attempt: &Attempt) -> Result<()> {
+        let output = (attempt.stdout.clone(), attempt.stderr.clone());
+        if self.previous_output.as_ref() == Some(&output) {
+            if self.echo != Echo::Off {
+                eprintln!("(same as last)");
+            }
+            return Ok(());
+        }
+        let (previous_stdout, previous_stderr) = match self.previous_output {
+            Some((ref stdout, ref stderr)) if self.diff_output => {
+                (stdout.as_ref(), stderr.as_ref())
+            }
+            _ => (None, None),
+        };
+        if let (Some(mut out), Some(ref stdout)) = (self.echo.stdout(), &output.0) {
+            let color = match self.echo {
+                Echo::Inherit => color_enabled_on(self.no_color, &io::stdout()),
+                _ => color_enabled(self.no_color),
+            };
+            write_output(&mut out, previous_stdout, stdout, color)?;
+        }
+        if let (Some(mut err), Some(ref stderr)) = (self.echo.stderr(), &output.1) {
+            write_output(
+                &mut err,
+                previous_stderr,
+                stderr,
+                color_enabled(self.no_color),
+            )?;
+        }
+        self.previous_output = Some(output);
+        Ok(())
+    }
+
+    /// Pass on the output of `attempt`, which was held back
+    fn replay_output(&self, attempt: &Attempt) -> Result<()> {
+        if let (Some(mut out), Some(ref stdout)) = (self.echo.stdout(), &attempt.stdout) {
+            write_output(&mut out, None, stdout, false)?;
+        }
+        if let (Some(mut err), Some(ref stderr)) = (self.echo.stderr(), &attempt.stderr) {
+            write_output(&mut err, None, stderr, false)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write `output` to `out`, or only the lines which changed since `previous`
+/// if given, in colour if `color`
+fn write_output(
+    out: &mut dyn Write,
+    previous: Option<&Vec<u8>>,
+    output: &[u8],
+    color: bool,
+) -> Result<()> {
+    match previous {
+        Some(previous) => {
+            let previous = String::from_utf8_lossy(previous);
+            let output = String::from_utf8_lossy(output);
+            for change in changed_lines(&previous, &output) {
+                let line = match change {
+                    Change::Removed(line) => paint(&format!("-{}", line), Color::Red, color),
+                    Change::Added(line) => paint(&format!("+{}", line), Color::Green, color),
+                };
+                writeln!(out, "{}", line)?;
+            }
+        }
+        None => out.write_all(output)?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Several tasks attempted together, which succeed once each of them has
+/// passed. A task which has passed isn't attempted again until the rest have
+/// caught up, unless passed tasks are rechecked.
+#[derive(Debug)]
+pub struct AllOf<T> {
+    tasks: Vec<T>,
+    passed: Vec<bool>,
+    /// What a task has to exit with to pass, unless its attempts are judged
+    /// otherwise
+    success_codes: Vec<i32>,
+    recheck_passed: bool,
+    /// How many tasks may be attempted at once, if they aren't attempted one
+    /// after another
+    parallel: Option<usize>,
+}
+
+impl<T: Task> AllOf<T> {
+    pub fn new(tasks: Vec<T>, options: &RetryOptions, recheck_passed: bool) -> AllOf<T> {
+        AllOf {
+            passed: vec![false; tasks.len()],
+            tasks,
+            success_codes: options.success_codes(),
+            recheck_passed,
+            parallel: None,
+        }
+    }
+
+    /// Attempt up to `max` tasks at once, each on its own thread
+    pub fn in_parallel(&mut self, max: usize) {
+        self.parallel = Some(max.max(1));
+    }
+}
+
+impl<T: Task + Send> AllOf<T> {
+    /// Attempt each task which hasn't passed yet, judging each attempt by
+    /// `passes`, and returning the first failure, or the attempts combined if
+    /// the tasks have now all passed
+    fn attempt_each(
+        &mut self,
+        loop_manager: &LoopManager,
+        passes: &dyn Fn(&Attempt) -> bool,
+    ) -> Result<(Attempt, bool)> {
+        let mut failure = None;
+        let mut round = Vec::new();
+        let recheck_passed = self.recheck_passed;
+        let mut due: Vec<_> = self
+            .tasks
+            .iter_mut()
+            .zip(self.passed.iter_mut())
+            .filter(|(_, passed)| !**passed || recheck_passed)
+            .collect();
+        for batch in due.chunks_mut(self.parallel.unwrap_or(1)) {
+            let attempts = match batch {
+                [(task, _)] => vec![task.attempt(loop_manager)],
+                _ => attempt_all(batch, loop_manager),
+            };
+            for ((_, passed), attempt) in batch.iter_mut().zip(attempts) {
+                let attempt = attempt?;
+                if let Outcome::Interrupted(_) = attempt.outcome {
+                    return Ok((attempt, false));
+                }
+                **passed = passes(&attempt);
+                if !**passed && failure.is_none() {
+                    failure = Some(attempt);
+                } else {
+                    round.push(attempt);
+                }
+            }
+        }
+        match failure {
+            Some(attempt) => Ok((attempt, false)),
+            None => {
+                // Start afresh, so that another success needs them all again
+                self.passed.iter_mut().for_each(|passed| *passed = false);
+                Ok((combined(round), true))
+            }
+        }
+    }
+}
+
+impl<T: Task + Send> Task for AllOf<T> {
+    /// Attempt each task which hasn't passed yet, counting those which exit
+    /// with a success code as passed
+    fn attempt(&mut self, loop_manager: &LoopManager) -> Result<Attempt> {
+        let success_codes = self.success_codes.clone();
+        let passes = |attempt: &Attempt| {
+            matches!(attempt.outcome, Outcome::Matched(_))
+                || attempt.code().is_some_and(|rc| success_codes.contains(&rc))
+        };
+        Ok(self.attempt_each(loop_manager, &passes)?.0)
+    }
+
+    fn attempt_judged(
+        &mut self,
+        loop_manager: &LoopManager,
+        passes: &dyn Fn(&Attempt) -> bool,
+    ) -> Result<(Attempt, Option<bool>)> {
+        let (attempt, passed) = self.attempt_each(loop_manager, passes)?;
+        Ok((attempt, Some(passed)))
+    }
+
+    fn runs_command(&self) -> bool {
+        self.tasks.iter().any(Task::runs_command)
+    }
+}
+
+/// The attempts of a round in which every task passed, as one, which finished
+/// as the first did, with the output of each in turn
+fn combined(attempts: Vec<Attempt>) -> Attempt {
+    let mut attempts = attempts.into_iter();
+    let mut combined = match attempts.next() {
+        Some(attempt) => attempt,
+        None => return Attempt::exited(ExitStatus::default()),
+    };
+    for attempt in attempts {
+        for (output, more) in [
+            (&mut combined.stdout, attempt.stdout),
+            (&mut combined.stderr, attempt.stderr),
+        ] {
+            if let Some(more) = more {
+                output.get_or_insert_with(Vec::new).extend(more);
+            }
+        }
+    }
+    combined
+}
+
+/// Attempt each of `tasks` at once, on threads of their own, each with its
+/// own copy of `loop_manager`
+fn attempt_all<T: Task + Send>(
+    tasks: &mut [(&mut T, &mut bool)],
+    loop_manager: &LoopManager,
+) -> Vec<Result<Attempt>> {
+    thread::scope(|scope| {
+        let threads: Vec<_> = tasks
+            .iter_mut()
+            .map(|(task, _)| {
+                let loop_manager = loop_manager.clone();
+                scope.spawn(move || task.attempt(&loop_manager))
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|_| Err(format_err!("An attempt panicked")))
+            })
+            .collect()
+    })
+}
+
+/// Have `command` run `n` nicer than us, each time it is spawned
+#[cfg(unix)]
+fn set_nice(command: &mut Command, n: i32) {
+    let nice = move || {
+        // nice can legitimately return -1, so only errno tells of a failure,
+        // such as not being allowed to raise the priority, once it has been
+        // cleared of whatever it held from before
+        unsafe { clear_errno() };
+        if unsafe { libc::nice(n) } == -1 {
+            let error = io::Error::last_os_error();
+            if let Some(libc::EPERM) | Some(libc::EACCES) = error.raw_os_error() {
+                return Err(error);
+            }
+        }
+        Ok(())
+    };
+    // Only async-signal-safe calls are made between fork and exec
+    unsafe {
+        command.pre_exec(nice);
+    }
+}
+
+/// Set errno to 0, for calls which only tell of failing through it
+#[cfg(unix)]
+unsafe fn clear_errno() {
+    #[cfg(any(target_os = "linux", target_os = "emscripten"))]
+    let errno = libc::__errno_location();
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "dragonfly"
+    ))]
+    let errno = libc::__error();
+    #[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+    let errno = libc::__errno();
+    *errno = 0;
+}
+
+/// Have `command` killed if we die before it finishes
+#[cfg(target_os = "linux")]
+fn die_with_parent(command: &mut Command) {
+    let watch = || {
+        if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    unsafe {
+        command.pre_exec(watch);
+    }
+}
+
+/// Have `command` limited to `bytes` of address space, so that it can't
+/// allocate any more than that
+#[cfg(target_os = "linux")]
+fn limit_memory(command: &mut Command, bytes: u64) {
+    let limit = move || {
+        let limit = libc::rlimit {
+            rlim_cur: bytes as libc::rlim_t,
+            rlim_max: bytes as libc::rlim_t,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    unsafe {
+        command.pre_exec(limit);
+    }
+}
+
+/// Memory can only be limited on Linux, which `RetryOptions::warn_unsupported`
+/// warns about
+#[cfg(not(target_os = "linux"))]
+fn limit_memory(_command: &mut Command, _bytes: u64) {}
+
+/// Have `command` run at a lower priority class if `n` is positive, as there
+/// are no finer grained priorities to choose from
+#[cfg(windows)]
+fn set_nice(command: &mut Command, n: i32) {
+    const IDLE_PRIORITY_CLASS: u32 = 0x0000_0040;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+    match n {
+        n if n >= 15 => {
+            command.creation_flags(IDLE_PRIORITY_CLASS);
+        }
+        n if n > 0 => {
+            command.creation_flags(BELOW_NORMAL_PRIORITY_CLASS);
+        }
+        // Raising the priority is warned about by
+        // `RetryOptions::warn_unsupported`
+        _ => (),
+    }
+}
+
+/// Priority can't be set here, which `RetryOptions::warn_unsupported` warns
+/// about
+#[cfg(not(any(unix, windows)))]
+fn set_nice(_command: &mut Command, _n: i32) {}
+
+/// `arg` quoted for the shell, if it needs to be
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// A shell command line which runs `command` as we do, including any
+/// environment variables we set on it
+fn command_line(command: &Command, env_clear: bool) -> String {
+    let mut words = Vec::new();
+    if env_clear {
+        words.push(String::from("env -i"));
+    }
+    for (key, value) in command.get_envs() {
+        if let Some(value) = value {
+            words.push(format!(
+                "{}={}",
+                key.to_string_lossy(),
+                shell_quote(&value.to_string_lossy())
+            ));
+        }
+    }
+    words.push(shell_quote(&command.get_program().to_string_lossy()));
+    words.extend(
+        command
+            .get_args()
+            .map(|arg| shell_quote(&arg.to_string_lossy())),
+    );
+    words.join(" ")
+}
+
+/// Open `path` to append the output of attempt number `attempt` to, headed
+/// with the attempt number and time
+fn open_tee(path: &Path, attempt: usize) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format_err!("Could not open {}: {}", path.display(), e))?;
+    writeln!(
+        file,
+        "=== Attempt {} at {} ===",
+        attempt,
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+    )?;
+    Ok(file)
+}
+
+/// How a piped output stream is captured
+#[derive(Debug, Clone)]
+pub struct Capture {
+    /// Most bytes kept, beyond which the output is cut short with a marker
+    pub limit: usize,
+    /// Whether each line passed through is prefixed with the time
+    pub timestamps: bool,
+    /// Where the lines passed through are also kept, if anywhere
+    pub tail: Option<Tail>,
+    /// Put at the start of each line passed through, to tell which command
+    /// it came from
+    pub label: Option<String>,
+    /// How long the command may go without any output before it is killed,
+    /// as if it had exceeded its timeout
+    pub idle_timeout: Option<Duration>,
+    /// A line to watch stdout for, which stops the command once it appears
+    pub until_line: Option<Regex>,
+}
+
+/// Longest line kept in a `Tail`, beyond which the rest of it is dropped
+const MAX_TAIL_LINE: usize = 4096;
+
+/// The last few lines of output, from however many attempts, shared between
+/// the threads capturing it
+#[derive(Debug, Clone)]
+pub struct Tail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    size: usize,
+}
+
+impl Tail {
+    /// Keeping the last `size` lines
+    pub fn new(size: usize) -> Tail {
+        Tail {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(size))),
+            size,
+        }
+    }
+
+    fn push(&self, line: &[u8]) {
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        if lines.len() == self.size {
+            lines.pop_front();
+        }
+        if self.size > 0 {
+            lines.push_back(String::from_utf8_lossy(line).into_owned());
+        }
+    }
+
+    /// The lines kept, oldest first
+    pub fn lines(&self) -> Vec<String> {
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        lines.iter().cloned().collect()
+    }
+
+    /// A stream whose lines are added to the tail, the last of them when it
+    /// is dropped if it doesn't end with a newline
+    fn writer(&self) -> TailWriter {
+        TailWriter {
+            tail: self.clone(),
+            partial: Vec::new(),
+        }
+    }
+}
+
+struct TailWriter {
+    tail: Tail,
+    partial: Vec<u8>,
+}
+
+impl Write for TailWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        for (i, line) in data.split(|&b| b == b'\n').enumerate() {
+            if i > 0 {
+                self.tail.push(&self.partial);
+                self.partial.clear();
+            }
+            let room = MAX_TAIL_LINE.saturating_sub(self.partial.len());
+            self.partial
+                .extend_from_slice(&line[..line.len().min(room)]);
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TailWriter {
+    fn drop(&mut self) {
+        if !self.partial.is_empty() {
+            self.tail.push(&self.partial);
+        }
+    }
+}
+
+/// Longest line checked against --until-line, beyond which the rest of it is
+/// ignored
+const MAX_MATCHED_LINE: usize = 64 * 1024;
+
+/// Watches output for a line matching a pattern, noting in `matched` once
+/// one appears
+struct LineTrigger {
+    pattern: Regex,
+    partial: Vec<u8>,
+    matched: Arc<AtomicBool>,
+}
+
+impl LineTrigger {
+    fn feed(&mut self, data: &[u8]) {
+        for piece in data.split_inclusive(|&b| b == b'\n') {
+            let room = MAX_MATCHED_LINE.saturating_sub(self.partial.len());
+            self.partial
+                .extend_from_slice(&piece[..piece.len().min(room)]);
+            if piece.ends_with(b"\n") {
+                self.check();
+            }
+        }
+    }
+
+    /// Check the line read so far, and start on the next
+    fn check(&mut self) {
+        let line = String::from_utf8_lossy(&self.partial);
+        if self.pattern.is_match(line.trim_end_matches(['\n', '\r'])) {
+            self.matched.store(true, Ordering::SeqCst);
+        }
+        self.partial.clear();
+    }
+
+    /// Check the last line, if it didn't end with a newline
+    fn finish(&mut self) {
+        if !self.partial.is_empty() {
+            self.check();
+        }
+    }
+}
+
+/// Marks where captured output of more than `limit` bytes was cut short
+fn truncated(limit: usize) -> String {
+    format!("\n[output truncated after {} bytes]\n", limit)
+}
+
+/// How long a stream is waited on, once the attempt has exited, with nothing
+/// written to it, before taking it to be held open by something the attempt
+/// left running
+const OUTPUT_DRAIN: Duration = Duration::from_millis(200);
+
+fn lock<T>(mutex: &Mutex<T>) -> ::std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A child's output stream being read on a thread of its own
+struct Reader {
+    captured: Arc<Mutex<Vec<u8>>>,
+    /// Since when the thread has been waiting for more to read, if it is
+    waiting: Arc<Mutex<Option<Instant>>>,
+    /// Set when the thread should stop passing on output, and finish
+    abandoned: Arc<AtomicBool>,
+    /// Disconnected once the thread has finished
+    done: Receiver<()>,
+}
+
+impl Reader {
+    /// The output captured, once the attempt has `exited`. The stream is
+    /// read to the end, unless something the attempt started, and left
+    /// running, keeps it open without writing to it, when the rest is left to
+    /// the thread to discard.
+    fn finish(self, exited: Instant) -> Vec<u8> {
+        while let Err(RecvTimeoutError::Timeout) = self.done.recv_timeout(POLL_INTERVAL) {
+            let waiting = *lock(&self.waiting);
+            if waiting.is_some_and(|since| since.max(exited).elapsed() >= OUTPUT_DRAIN) {
+                debug!("Output is held open by processes the attempt left, not waiting for it");
+                self.abandoned.store(true, Ordering::SeqCst);
+                break;
+            }
+        }
+        let captured = lock(&self.captured).split_off(0);
+        captured
+    }
+}
+
+/// Read a child's output stream to the end on a separate thread, so the
+/// child can't block on a full pipe, noting the time of any output in
+/// `activity`. Everything read is copied to each of `outputs`, but only the
+/// first `limit` bytes are kept, followed by `truncated` if there was more.
+/// Labelled output is only passed on in whole lines, so that it can't be
+/// mixed up with the output of attempts running alongside.
+fn capture<R>(
+    mut stream: R,
+    mut outputs: Vec<Box<dyn Write + Send>>,
+    capture: &Capture,
+    activity: Arc<Mutex<Instant>>,
+    mut trigger: Option<LineTrigger>,
+) -> Reader
+where
+    R: Read + Send + 'static,
+{
+    let (limit, timestamps, label) = (capture.limit, capture.timestamps, capture.label.clone());
+    let (done, finished) = mpsc::channel();
+    let reader = Reader {
+        captured: Arc::new(Mutex::new(Vec::new())),
+        waiting: Arc::new(Mutex::new(None)),
+        abandoned: Arc::new(AtomicBool::new(false)),
+        done: finished,
+    };
+    let (kept, waiting, abandoned) = (
+        reader.captured.clone(),
+        reader.waiting.clone(),
+        reader.abandoned.clone(),
+    );
+    thread::spawn(move || {
+        // Dropped when the thread finishes, however it does
+        let _done: mpsc::Sender<()> = done;
+        let mut buffer = [0; 8192];
+        let mut cut = false;
+        let mut line_start = true;
+        // The start of a labelled line which hasn't been passed on yet
+        let mut partial = Vec::new();
+        let show =
+            |outputs: &mut Vec<Box<dyn Write + Send>>, data: &[u8], line_start: &mut bool| {
+                let stamped;
+                let shown = if timestamps || label.is_some() {
+                    stamped = stamp(data, line_start, timestamps, label.as_deref());
+                    &stamped[..]
+                } else {
+                    data
+                };
+                for output in outputs.iter_mut() {
+                    let _ = output.write_all(shown).and_then(|_| output.flush());
+                }
+            };
+        loop {
+            *lock(&waiting) = Some(Instant::now());
+            let read = stream.read(&mut buffer);
+            *lock(&waiting) = None;
+            if abandoned.load(Ordering::SeqCst) {
+                return;
+            }
+            let n = match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            *lock(&activity) = Instant::now();
+            if let Some(ref mut trigger) = trigger {
+                trigger.feed(&buffer[..n]);
+            }
+            if label.is_some() {
+                partial.extend_from_slice(&buffer[..n]);
+                // A very long line is passed on in pieces all the same
+                let whole = match partial.iter().rposition(|&b| b == b'\n') {
+                    Some(i) => i + 1,
+                    None if partial.len() >= buffer.len() => partial.len(),
+                    None => 0,
+                };
+                let lines: Vec<u8> = partial.drain(..whole).collect();
+                show(&mut outputs, &lines, &mut line_start);
+            } else {
+                show(&mut outputs, &buffer[..n], &mut line_start);
+            }
+            let mut captured = lock(&kept);
+            let keep = n.min(limit - captured.len());
+            captured.extend_from_slice(&buffer[..keep]);
+            cut |= keep < n;
+        }
+        if !partial.is_empty() {
+            show(&mut outputs, &partial, &mut line_start);
+        }
+        if let Some(ref mut trigger) = trigger {
+            trigger.finish();
+        }
+        // End a partial last line, so the next attempt's output starts on a
+        // line (and under a timestamp) of its own
+        if !line_start {
+            show(&mut outputs, b"\n", &mut line_start);
+        }
+        if cut {
+            lock(&kept).extend_from_slice(truncated(limit).as_bytes());
+        }
+    });
+    reader
+}
+
+/// `data` with the time, if `timestamps`, and `label`, if given, at the
+/// start of each line, where `line_start` says whether `data` starts a line,
+/// and is updated to say whether the next data will
+fn stamp(data: &[u8], line_start: &mut bool, timestamps: bool, label: Option<&str>) -> Vec<u8> {
+    let mut prefix = String::new();
+    if timestamps {
+        prefix += &format!(
+            "[{}] ",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+        );
+    }
+    if let Some(label) = label {
+        prefix += &format!("[{}] ", label);
+    }
+    let mut stamped = Vec::with_capacity(data.len() + prefix.len());
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        if *line_start {
+            stamped.extend_from_slice(prefix.as_bytes());
+        }
+        stamped.extend_from_slice(line);
+        *line_start = line.ends_with(b"\n");
+    }
+    stamped
+}
+
+/// Write `input` to a child's stdin on a separate thread. The thread is left
+/// detached, and gives up quietly if the child closes its stdin early, so a
+/// child which doesn't read everything can't block us.
+fn feed<W>(mut stdin: W, input: &[u8])
+where
+    W: Write + Send + 'static,
+{
+    let input = input.to_vec();
+    thread::spawn(move || {
+        // Dropping stdin at the end closes it, so the child sees end of file
+        let _ = stdin.write_all(&input);
+    });
+}
+
+/// Where a captured stream is copied to: `echo`, and `tee` and `tail` if
+/// given
+fn outputs(
+    echo: Option<Box<dyn Write + Send>>,
+    tee: Option<&File>,
+    tail: Option<&Tail>,
+) -> Result<Vec<Box<dyn Write + Send>>> {
+    let mut outputs = Vec::new();
+    outputs.extend(echo);
+    if let Some(file) = tee {
+        outputs.push(Box::new(file.try_clone()?));
+    }
+    if let Some(tail) = tail {
+        outputs.push(Box::new(tail.writer()));
+    }
+    Ok(outputs)
+}
+
+/// Run the command once, killing it if it outlives `timeout` or we are
+/// interrupted. On a timeout, the command is first sent SIGTERM if
+/// `kill_grace` is given, and only killed if it is still running that much
+/// later. If `input` is given, it is written to the command's stdin, which
+/// should be piped. Any piped output is captured, passed through to our own
+/// output as per `echo`, and appended to `tee` if given, as per `capture`.
+pub fn run_attempt(
+    command: &mut Command,
+    input: Option<&[u8]>,
+    timeout: Option<Duration>,
+    kill_grace: Option<Duration>,
+    echo: Echo,
+    capture: Capture,
+    tee: Option<&File>,
+) -> Result<Attempt> {
+    let foreground = process_group::isolate(command);
+    let spawned = command.spawn().map_err(|e| spawn_error(command, e))?;
+    let mut child = ChildGuard::new(spawned, foreground);
+    let activity = Arc::new(Mutex::new(Instant::now()));
+    let matched = Arc::new(AtomicBool::new(false));
+    if let (Some(stdin), Some(input)) = (child.stdin.take(), input) {
+        feed(stdin, input);
+    }
+    let trigger = capture.until_line.clone().map(|pattern| LineTrigger {
+        pattern,
+        partial: Vec::new(),
+        matched: matched.clone(),
+    });
+    let stdout = match child.stdout.take() {
+        Some(out) => Some(self::capture(
+            out,
+            outputs(echo.stdout(), tee, capture.tail.as_ref())?,
+            &capture,
+            activity.clone(),
+            trigger,
+        )),
+        None => None,
+    };
+    let stderr = match child.stderr.take() {
+        Some(err) => Some(self::capture(
+            err,
+            outputs(echo.stderr(), tee, capture.tail.as_ref())?,
+            &capture,
+            activity.clone(),
+            None,
+        )),
+        None => None,
+    };
+
+    let idle = capture.idle_timeout.map(|t| (&*activity, t));
+    let outcome = wait(&mut child, timeout, idle, kill_grace, &matched)?;
+    let exited = Instant::now();
+
+    Ok(Attempt {
+        outcome,
+        stdout: stdout.map(|reader| reader.finish(exited)),
+        stderr: stderr.map(|reader| reader.finish(exited)),
+    })
+}
+
+/// A running child, which is killed and reaped when this is dropped if it
+/// is still running, so that returning early with an error can't leave it
+/// behind. Stopping it stops its process group too, so that a shell's
+/// children go with it.
+struct ChildGuard {
+    child: Child,
+    group: ProcessGroup,
+}
+
+impl ChildGuard {
+    /// Guard `child`, which has the terminal if `foreground`
+    fn new(child: Child, foreground: bool) -> ChildGuard {
+        let group = ProcessGroup::of(&child, foreground);
+        ChildGuard { child, group }
+    }
+}
+
+impl Deref for ChildGuard {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl DerefMut for ChildGuard {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        // Once reaped, the child's status is kept, so it isn't waited for
+        // again
+        if let Ok(None) = self.child.try_wait() {
+            debug!("Killing attempt {} left running", self.child.id());
+            let _ = kill(self);
+        }
+    }
+}
+
+/// Why `command` couldn't be started
+fn spawn_error(command: &Command, error: io::Error) -> Error {
+    let program = command.get_program().to_string_lossy().into_owned();
+    match error.kind() {
+        io::ErrorKind::NotFound => RetryError::NotFound(program).into(),
+        _ => RetryError::CannotRun(program, error.to_string()).into(),
+    }
+}
+
+/// A status as if the command exited with `code`, for an attempt which
+/// never got to run
+#[cfg(unix)]
+pub(crate) fn exit_status(code: i32) -> ExitStatus {
+    ExitStatus::from_raw(code << 8)
+}
+
+#[cfg(windows)]
+pub(crate) fn exit_status(code: i32) -> ExitStatus {
+    ExitStatus::from_raw(code as u32)
+}
+
+/// Wait for the child to exit, stopping it if it outlives `timeout`, if
+/// the time of its last output in `idle` is longer ago than allowed, or once
+/// `matched` says it printed the line being waited for
+fn wait(
+    child: &mut ChildGuard,
+    timeout: Option<Duration>,
+    idle: Option<(&Mutex<Instant>, Duration)>,
+    kill_grace: Option<Duration>,
+    matched: &AtomicBool,
+) -> Result<Outcome> {
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            child.group.exited(status);
+            return Ok(Outcome::Exited(status));
+        }
+        child.group.follow_stop();
+        if matched.load(Ordering::SeqCst) {
+            debug!("Attempt printed the line waited for, stopping it");
+            return Ok(Outcome::Matched(terminate(child, kill_grace)?));
+        }
+        if signals::interrupted() {
+            return Ok(Outcome::Interrupted(kill(child)?));
+        }
+        if let Some(t) = timeout {
+            if started.elapsed() >= t {
+                return Ok(Outcome::TimedOut(terminate(child, kill_grace)?));
+            }
+        }
+        if let Some((activity, t)) = idle {
+            let last = *activity.lock().unwrap_or_else(|e| e.into_inner());
+            if last.elapsed() >= t {
+                debug!("No output for {:?}, stopping the attempt", t);
+                return Ok(Outcome::TimedOut(terminate(child, kill_grace)?));
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Ask the child to exit with SIGTERM, killing it if it is still running
+/// after `grace`
+#[cfg(unix)]
+fn terminate(child: &mut ChildGuard, grace: Option<Duration>) -> Result<ExitStatus> {
+    let grace = match grace {
+        Some(grace) => grace,
+        None => return kill(child),
+    };
+    child.group.signal(&child.child, libc::SIGTERM);
+    let started = Instant::now();
+    while started.elapsed() < grace {
+        if let Some(status) = child.try_wait()? {
+            debug!("Attempt exited after SIGTERM: {}", status);
+            return Ok(status);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    debug!(
+        "Attempt still running {:?} after SIGTERM, sending SIGKILL",
+        grace
+    );
+    kill(child)
+}
+
+/// Kill the child, as there is no gentler way to stop it here
+#[cfg(not(unix))]
+fn terminate(child: &mut ChildGuard, _grace: Option<Duration>) -> Result<ExitStatus> {
+    kill(child)
+}
+
+fn kill(child: &mut ChildGuard) -> Result<ExitStatus> {
+    // The child may have exited on its own in the meantime, for example if it
+    // got the same Ctrl-C we did
+    let _ = child.group.kill(&mut child.child);
+    // Reap the killed child so it doesn't linger as a zombie
+    Ok(child.wait()?)
+}
+
+// These look for processes in /proc
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use quicli::prelude::*;
+    use std::fs;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{run_attempt, Capture, ChildGuard, Echo, Outcome};
+
+    fn plain() -> Capture {
+        Capture {
+            limit: 1 << 20,
+            timestamps: false,
+            tail: None,
+            label: None,
+            idle_timeout: None,
+            until_line: None,
+        }
+    }
+
+    /// Run `script` with sh once, capturing its stdout, timing out after
+    /// `timeout`, returning the outcome, the pid it printed and how long it
+    /// took
+    fn run_script(script: &str, timeout: Option<Duration>) -> (Outcome, u32, Duration) {
+        let mut command = Command::new("sh");
+        command.args(["-c", script]).stdout(Stdio::piped());
+        let started = Instant::now();
+        let attempt = run_attempt(&mut command, None, timeout, None, Echo::Off, plain(), None)
+            .expect("the attempt ran");
+        let stdout = String::from_utf8_lossy(&attempt.stdout.unwrap_or_default()).into_owned();
+        let pid = stdout.trim().parse().expect("a pid");
+        (attempt.outcome, pid, started.elapsed())
+    }
+
+    /// Whether the process `pid` is still running, rather than gone or dead
+    /// and waiting to be reaped by whoever it was orphaned to
+    fn running(pid: u32) -> bool {
+        match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            // The state follows the name, which is in brackets
+            Ok(stat) => !stat.rsplit(')').next().unwrap_or("").starts_with(" Z"),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether `pid` stops running within a few seconds
+    fn stops(pid: u32) -> bool {
+        let started = Instant::now();
+        while running(pid) {
+            if started.elapsed() > Duration::from_secs(5) {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    /// Whether the process `pid` is gone, having been reaped
+    fn reaped(pid: u32) -> bool {
+        !Path::new(&format!("/proc/{}", pid)).exists()
+    }
+
+    #[test]
+    fn a_child_is_reaped_when_returning_early() {
+        fn fails_part_way(pid: &mut u32) -> Result<()> {
+            let child = ChildGuard::new(Command::new("sleep").arg("30").spawn()?, false);
+            *pid = child.id();
+            bail!("Something went wrong while it ran");
+        }
+        let mut pid = 0;
+        assert!(fails_part_way(&mut pid).is_err());
+        assert!(pid != 0 && reaped(pid));
+    }
+
+    #[test]
+    fn a_timeout_stops_what_the_attempt_started() {
+        let timeout = Duration::from_millis(200);
+        let (outcome, pid, took) = run_script("sleep 30 & echo $!; wait", Some(timeout));
+        assert!(matches!(outcome, Outcome::TimedOut(_)));
+        assert!(took < Duration::from_secs(5));
+        assert!(stops(pid));
+    }
+
+    #[test]
+    fn output_held_open_by_something_left_running_is_not_waited_for() {
+        let (outcome, pid, took) = run_script("setsid sleep 30 & echo $!", None);
+        assert!(matches!(outcome, Outcome::Exited(status) if status.success()));
+        assert!(took < Duration::from_secs(5));
+        assert!(running(pid));
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+    }
+}
@@ -0,0 +1,54 @@
+use chrono::{SecondsFormat, Utc};
+use quicli::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// A log with a line per attempt, of the form
+///
+/// ```text
+/// <RFC 3339 time>\t<attempt number>\t<duration in ms>\t<exit code>
+/// ```
+///
+/// where the exit code is empty if the command was killed
+#[derive(Debug)]
+pub(crate) struct AttemptLog {
+    file: File,
+}
+
+impl AttemptLog {
+    /// Open `path` for appending, creating it if need be
+    pub(crate) fn open(path: &Path) -> Result<AttemptLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format_err!("Could not open log file {}: {}", path.display(), e))?;
+        Ok(AttemptLog { file })
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        attempt: usize,
+        duration: Duration,
+        code: Option<i32>,
+    ) -> Result<()> {
+        let line = format!(
+            "{}\t{}\t{}\t{}\n",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            attempt,
+            duration.as_millis(),
+            code.map(|rc| rc.to_string()).unwrap_or_default()
+        );
+        append_line(&mut self.file, &line)
+    }
+}
+
+/// Append `line` to `file` with a single write, flushed straight away, so that
+/// the file is complete up to the last line even if we are killed
+pub(crate) fn append_line(file: &mut File, line: &str) -> Result<()> {
+    file.write_all(line.as_bytes())?;
+    file.flush()?;
+    Ok(())
+}
@@ -0,0 +1,419 @@
+use quicli::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use loop_manager::milliseconds;
+
+/// Decides how long to wait between attempts. Implement this to plug a custom
+/// backoff into a `LoopManager` with `set_backoff`.
+pub trait Backoff: fmt::Debug + BackoffClone + Send {
+    /// The wait after attempt `iteration` (counting from 1), before the next,
+    /// `elapsed` into retrying
+    fn next_delay(&mut self, iteration: usize, elapsed: Duration) -> Duration;
+
+    /// Whether the waits lay out a schedule, so that a slow attempt eats into
+    /// the following wait, rather than each wait being counted from the end
+    /// of the last attempt
+    fn scheduled(&self) -> bool {
+        true
+    }
+
+    /// Whether the waits never get shorter, so that once one reaches the
+    /// longest wait allowed, all the rest will too
+    fn grows(&self) -> bool {
+        false
+    }
+
+    /// Forget any state, as the backoff is starting again from the first wait
+    fn reset(&mut self) {}
+
+    /// What the backoff carries from one wait to the next besides the
+    /// iteration, if anything, so that it can be saved with --checkpoint
+    fn state(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Carry on from `state`, as given by `state` in an earlier run
+    fn restore(&mut self, _state: Duration) {}
+}
+
+/// Lets a `LoopManager` be cloned along with its backoff; implemented for
+/// every `Backoff` which is `Clone`
+pub trait BackoffClone {
+    fn clone_box(&self) -> Box<dyn Backoff>;
+}
+
+impl<T: Backoff + Clone + 'static> BackoffClone for T {
+    fn clone_box(&self) -> Box<dyn Backoff> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Backoff> {
+    fn clone(&self) -> Box<dyn Backoff> {
+        self.clone_box()
+    }
+}
+
+fn seconds(time_s: f64) -> Duration {
+    Duration::from_millis(milliseconds(time_s))
+}
+
+/// Waits the interval every time
+#[derive(Debug, Clone)]
+pub struct Constant {
+    pub interval: f64,
+}
+
+impl Backoff for Constant {
+    fn next_delay(&mut self, _iteration: usize, _elapsed: Duration) -> Duration {
+        seconds(self.interval)
+    }
+
+    fn grows(&self) -> bool {
+        true
+    }
+}
+
+/// The nth wait is interval * n
+#[derive(Debug, Clone)]
+pub struct Linear {
+    pub interval: f64,
+}
+
+impl Backoff for Linear {
+    fn next_delay(&mut self, iteration: usize, _elapsed: Duration) -> Duration {
+        seconds(self.interval * iteration as f64)
+    }
+
+    fn grows(&self) -> bool {
+        true
+    }
+}
+
+/// The nth wait is interval * base^(n - 1)
+#[derive(Debug, Clone)]
+pub struct Exponential {
+    pub interval: f64,
+    pub base: f64,
+}
+
+impl Backoff for Exponential {
+    fn next_delay(&mut self, iteration: usize, _elapsed: Duration) -> Duration {
+        seconds(self.interval * self.base.powf(iteration as f64 - 1.0))
+    }
+
+    fn grows(&self) -> bool {
+        self.base >= 1.0
+    }
+}
+
+/// Longest single wait fibonacci and decorrelated jitter backoff will grow
+/// to (in seconds)
+const BACKOFF_CEILING_S: f64 = 24.0 * 60.0 * 60.0;
+
+/// The nth wait is interval * fib(n), i.e. 1, 1, 2, 3, 5... intervals, keeping
+/// the sequence so far so that each new wait costs a single addition
+#[derive(Debug, Clone)]
+pub struct Fibonacci {
+    interval: f64,
+    iteration: usize,
+    current: f64,
+    next: f64,
+}
+
+impl Fibonacci {
+    pub fn new(interval: f64) -> Fibonacci {
+        Fibonacci {
+            interval,
+            iteration: 0,
+            current: 0.0,
+            next: 1.0,
+        }
+    }
+}
+
+impl Backoff for Fibonacci {
+    fn next_delay(&mut self, iteration: usize, _elapsed: Duration) -> Duration {
+        if self.interval <= 0.0 {
+            return Duration::from_secs(0);
+        }
+        if iteration < self.iteration {
+            *self = Fibonacci::new(self.interval);
+        }
+        // The numbers stop growing once the wait reaches the ceiling
+        let cap = BACKOFF_CEILING_S / self.interval;
+        while self.iteration < iteration {
+            let following = (self.current + self.next).min(cap);
+            self.current = self.next;
+            self.next = following;
+            self.iteration += 1;
+        }
+        seconds(self.interval * self.current)
+    }
+
+    fn grows(&self) -> bool {
+        true
+    }
+}
+
+/// Each wait is random, between interval and three times the previous wait,
+/// as recommended for spreading out retries from many clients. Without a
+/// max interval, the waits grow no longer than a day.
+#[derive(Debug, Clone)]
+pub struct DecorrelatedJitter {
+    interval: f64,
+    max_interval: Option<f64>,
+    previous: Option<Duration>,
+    rng: StdRng,
+}
+
+impl DecorrelatedJitter {
+    /// Waits which grow no longer than `max_interval`, so that they can
+    /// shrink again
+    pub fn new(interval: f64, max_interval: Option<f64>) -> DecorrelatedJitter {
+        DecorrelatedJitter::seeded(interval, max_interval, StdRng::from_entropy())
+    }
+
+    /// Likewise, with the waits drawn from `rng`, such as one made from a
+    /// seed for waits which are the same every time
+    pub fn seeded(interval: f64, max_interval: Option<f64>, rng: StdRng) -> DecorrelatedJitter {
+        DecorrelatedJitter {
+            interval,
+            max_interval,
+            previous: None,
+            rng,
+        }
+    }
+}
+
+impl Backoff for DecorrelatedJitter {
+    fn next_delay(&mut self, _iteration: usize, _elapsed: Duration) -> Duration {
+        let base = seconds(self.interval);
+        let longest = seconds(self.max_interval.unwrap_or(BACKOFF_CEILING_S));
+        let ceiling = self
+            .previous
+            .unwrap_or(base)
+            .saturating_mul(3)
+            .min(longest.max(base));
+        let wait = if ceiling > base {
+            self.rng.gen_range(base..=ceiling)
+        } else {
+            base
+        };
+        let wait = wait.min(longest);
+        self.previous = Some(wait);
+        wait
+    }
+
+    fn scheduled(&self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {
+        self.previous = None;
+    }
+
+    fn state(&self) -> Option<Duration> {
+        self.previous
+    }
+
+    fn restore(&mut self, state: Duration) {
+        self.previous = Some(state);
+    }
+}
+
+/// Waits given one by one, after which the last is repeated
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub waits: Vec<f64>,
+}
+
+impl Backoff for Schedule {
+    fn next_delay(&mut self, iteration: usize, _elapsed: Duration) -> Duration {
+        let n = iteration.clamp(1, self.waits.len().max(1));
+        seconds(self.waits.get(n - 1).cloned().unwrap_or(0.0))
+    }
+}
+
+/// How a wait is randomised before it is taken
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JitterMode {
+    /// Vary the wait by up to the jitter fraction either way
+    Proportional,
+    /// Wait a random time up to the wait
+    Full,
+    /// Wait half the wait, plus a random time up to the other half
+    Equal,
+}
+
+impl JitterMode {
+    /// `wait` with jitter applied, where `jitter` is the fraction a
+    /// proportional jitter varies the wait by
+    pub fn apply<R: Rng>(self, wait: Duration, jitter: f64, rng: &mut R) -> Duration {
+        match self {
+            JitterMode::Proportional if jitter == 0.0 => wait,
+            JitterMode::Proportional => wait.mul_f64(rng.gen_range(1.0 - jitter..=1.0 + jitter)),
+            JitterMode::Full => wait.mul_f64(rng.gen_range(0.0..=1.0)),
+            JitterMode::Equal => wait / 2 + (wait / 2).mul_f64(rng.gen_range(0.0..=1.0)),
+        }
+    }
+}
+
+impl FromStr for JitterMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "proportional" => Ok(JitterMode::Proportional),
+            "full" => Ok(JitterMode::Full),
+            "equal" => Ok(JitterMode::Equal),
+            _ => bail!("Unknown jitter mode: {}", s),
+        }
+    }
+}
+
+/// The built in backoffs, as chosen on the command line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackoffStrategy {
+    /// The nth wait is interval
+    Constant,
+    /// The nth wait is interval * n
+    Linear,
+    /// The nth wait is interval * base^(n - 1)
+    Exponential,
+    /// The nth wait is interval * fib(n), i.e. 1, 1, 2, 3, 5... intervals
+    Fibonacci,
+    /// Each wait is random, between interval and three times the previous
+    /// wait, as recommended for spreading out retries from many clients
+    DecorrelatedJitter,
+}
+
+impl BackoffStrategy {
+    /// This strategy, starting from `interval` seconds, with any randomness
+    /// drawn from a generator seeded with `seed`
+    pub fn backoff(
+        self,
+        interval: f64,
+        base: f64,
+        max_interval: Option<f64>,
+        seed: u64,
+    ) -> Box<dyn Backoff> {
+        match self {
+            BackoffStrategy::Constant => Box::new(Constant { interval }),
+            BackoffStrategy::Linear => Box::new(Linear { interval }),
+            BackoffStrategy::Exponential => Box::new(Exponential { interval, base }),
+            BackoffStrategy::Fibonacci => Box::new(Fibonacci::new(interval)),
+            BackoffStrategy::DecorrelatedJitter => Box::new(DecorrelatedJitter::seeded(
+                interval,
+                max_interval,
+                StdRng::seed_from_u64(seed),
+            )),
+        }
+    }
+}
+
+impl FromStr for BackoffStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "constant" => Ok(BackoffStrategy::Constant),
+            "linear" => Ok(BackoffStrategy::Linear),
+            "exponential" => Ok(BackoffStrategy::Exponential),
+            "fibonacci" => Ok(BackoffStrategy::Fibonacci),
+            "decorrelated-jitter" => Ok(BackoffStrategy::DecorrelatedJitter),
+            _ => bail!("Unknown backoff strategy: {}", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// The first `n` waits `strategy` gives, starting from a 1s interval
+    fn waits(strategy: BackoffStrategy, n: usize) -> Vec<Duration> {
+        let mut backoff = strategy.backoff(1.0, 2.0, None, 0);
+        (1..=n)
+            .map(|i| backoff.next_delay(i, Duration::from_secs(0)))
+            .collect()
+    }
+
+    fn secs(waits: &[u64]) -> Vec<Duration> {
+        waits.iter().map(|&s| Duration::from_secs(s)).collect()
+    }
+
+    #[test]
+    fn constant_waits_the_interval() {
+        assert_eq!(waits(BackoffStrategy::Constant, 3), secs(&[1, 1, 1]));
+    }
+
+    #[test]
+    fn linear_waits_grow_by_the_interval() {
+        assert_eq!(waits(BackoffStrategy::Linear, 3), secs(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn exponential_waits_grow_by_the_base() {
+        assert_eq!(waits(BackoffStrategy::Exponential, 3), secs(&[1, 2, 4]));
+    }
+
+    #[test]
+    fn decorrelated_jitter_is_the_same_for_the_same_seed() {
+        let first = waits(BackoffStrategy::DecorrelatedJitter, 20);
+        assert_eq!(first, waits(BackoffStrategy::DecorrelatedJitter, 20));
+        let base = Duration::from_secs(1);
+        assert!(first.iter().all(|&wait| wait >= base));
+        assert!(first.windows(2).all(|pair| pair[1] <= pair[0] * 3));
+    }
+
+    #[test]
+    fn decorrelated_jitter_stops_growing_without_a_max_interval() {
+        let waits = waits(BackoffStrategy::DecorrelatedJitter, 200);
+        let ceiling = Duration::from_secs(BACKOFF_CEILING_S as u64);
+        assert!(waits.iter().all(|&wait| wait <= ceiling));
+    }
+
+    /// `wait` with jitter applied by `mode` with each of a run of seeds
+    fn jittered(mode: JitterMode, wait: Duration, jitter: f64) -> Vec<Duration> {
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..100)
+            .map(|_| mode.apply(wait, jitter, &mut rng))
+            .collect()
+    }
+
+    #[test]
+    fn proportional_jitter_varies_the_wait_by_up_to_the_fraction() {
+        let wait = Duration::from_secs(10);
+        let waits = jittered(JitterMode::Proportional, wait, 0.2);
+        assert!(waits
+            .iter()
+            .all(|&w| w >= Duration::from_secs(8) && w <= Duration::from_secs(12)));
+        assert!(waits.iter().any(|&w| w != wait));
+        assert!(jittered(JitterMode::Proportional, wait, 0.0)
+            .iter()
+            .all(|&w| w == wait));
+    }
+
+    #[test]
+    fn full_jitter_waits_up_to_the_wait() {
+        let wait = Duration::from_secs(10);
+        let waits = jittered(JitterMode::Full, wait, 0.0);
+        assert!(waits.iter().all(|&w| w <= wait));
+        assert!(waits.iter().any(|&w| w < wait / 2));
+    }
+
+    #[test]
+    fn equal_jitter_waits_at_least_half_the_wait() {
+        let wait = Duration::from_secs(10);
+        let waits = jittered(JitterMode::Equal, wait, 0.0);
+        assert!(waits.iter().all(|&w| w >= wait / 2 && w <= wait));
+        assert_eq!(waits, jittered(JitterMode::Equal, wait, 0.0));
+    }
+}
@@ -0,0 +1,179 @@
+use quicli::prelude::*;
+use serde_json;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use loop_manager::LoopManager;
+
+/// How far retrying has got, saved with --checkpoint so that a later run can
+/// carry on from it with --resume
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    /// Attempts made so far
+    attempts: usize,
+    /// Attempts since the backoff last started again, which
+    /// --reset-backoff-after and --watch can make fewer than the attempts
+    backoff_iteration: usize,
+    /// Whether the waits had grown to --max-interval
+    steady: bool,
+    /// What the backoff carries from one wait to the next, such as the last
+    /// wait with decorrelated jitter, in milliseconds. The random draws
+    /// aren't saved, so they start afresh, from --seed if it is given.
+    backoff_ms: Option<u64>,
+    /// Time spent retrying so far
+    elapsed_ms: u64,
+    failures: usize,
+    consecutive_failures: usize,
+    /// When this was saved, in milliseconds since the Unix epoch
+    saved_ms: u64,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl Checkpoint {
+    /// The state of `loop_manager`, between attempts
+    pub(crate) fn of(loop_manager: &LoopManager) -> Result<Checkpoint> {
+        Ok(Checkpoint {
+            attempts: loop_manager.iteration,
+            backoff_iteration: loop_manager.backoff_iteration,
+            steady: loop_manager.steady,
+            backoff_ms: loop_manager
+                .backoff
+                .state()
+                .map(|state| state.as_millis() as u64),
+            elapsed_ms: loop_manager.elapsed()?.as_millis() as u64,
+            failures: loop_manager.failures,
+            consecutive_failures: loop_manager.consecutive_failures,
+            saved_ms: now_ms(),
+        })
+    }
+
+    /// Write this to `path`, replacing it in one go, so that a crash can't
+    /// leave it half written
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".tmp");
+        fs::write(&partial, serde_json::to_string(self)? + "\n")
+            .and_then(|()| fs::rename(&partial, path))
+            .map_err(|e| format_err!("Could not write checkpoint {}: {}", path.display(), e))
+    }
+
+    /// The checkpoint saved at `path`, if there is one
+    pub(crate) fn load(path: &Path) -> Result<Option<Checkpoint>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => bail!("Could not read checkpoint {}: {}", path.display(), e),
+        };
+        serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|e| format_err!("Invalid checkpoint {}: {}", path.display(), e))
+    }
+
+    /// Remove the checkpoint at `path`, once retrying is over
+    pub(crate) fn remove(path: &Path) -> Result<()> {
+        match fs::remove_file(path) {
+            Err(ref e) if e.kind() != io::ErrorKind::NotFound => {
+                bail!("Could not remove checkpoint {}: {}", path.display(), e)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Carry on from this checkpoint in `loop_manager`, counting the time
+    /// since it was saved as time spent retrying if `count_downtime`
+    pub(crate) fn restore(&self, loop_manager: &mut LoopManager, count_downtime: bool) {
+        let mut elapsed = Duration::from_millis(self.elapsed_ms);
+        if count_downtime {
+            elapsed += Duration::from_millis(now_ms().saturating_sub(self.saved_ms));
+        }
+        loop_manager.start_at(self.attempts);
+        loop_manager.backoff_iteration = self.backoff_iteration;
+        loop_manager.steady = self.steady;
+        if let Some(ms) = self.backoff_ms {
+            loop_manager.backoff.restore(Duration::from_millis(ms));
+        }
+        loop_manager.failures = self.failures;
+        loop_manager.consecutive_failures = self.consecutive_failures;
+        loop_manager.carried_over = elapsed;
+        // The next attempt is due straight away, with the backoff carrying on
+        // from there
+        loop_manager.next_due = elapsed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quicli::prelude::*;
+    use serde_json;
+    use std::iter;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::Checkpoint;
+    use clock::MockClock;
+    use loop_manager::LoopManager;
+    use options::RetryOptions;
+
+    fn loop_manager(args: &[&str]) -> LoopManager {
+        let options = RetryOptions::from_iter(iter::once("retry").chain(args.iter().cloned()));
+        let mut loop_manager = options.build_loop_manager();
+        loop_manager.set_clock(Arc::new(MockClock::new()));
+        loop_manager
+    }
+
+    /// `loop_manager` saved to a checkpoint and restored into a fresh loop
+    /// manager for `args`
+    fn resume(loop_manager: &LoopManager, args: &[&str]) -> LoopManager {
+        let saved = serde_json::to_string(&Checkpoint::of(loop_manager).unwrap()).unwrap();
+        let checkpoint: Checkpoint = serde_json::from_str(&saved).unwrap();
+        let mut restored = self::loop_manager(args);
+        checkpoint.restore(&mut restored, false);
+        restored
+    }
+
+    #[test]
+    fn resuming_carries_on_the_backoff_where_it_was() {
+        let args = ["-i", "1", "--backoff", "linear", "--max-interval", "3"];
+        let mut original = loop_manager(&args);
+        for _ in 0..4 {
+            original.step().unwrap();
+        }
+        original.reset_backoff().unwrap();
+        original.step().unwrap();
+        let mut resumed = resume(&original, &args);
+        assert_eq!(resumed.iteration(), 5);
+        assert_eq!(resumed.backoff_iteration, 2);
+        assert_eq!(resumed.interval().unwrap(), Duration::from_secs(2));
+
+        for _ in 0..3 {
+            original.step().unwrap();
+            original.interval().unwrap();
+        }
+        assert!(original.steady);
+        assert!(resume(&original, &args).steady);
+    }
+
+    #[test]
+    fn resuming_carries_on_decorrelated_jitter_from_the_last_wait() {
+        let args = ["-i", "1", "--backoff", "decorrelated-jitter", "--seed", "7"];
+        let mut original = loop_manager(&args);
+        for _ in 0..3 {
+            original.step().unwrap();
+            original.interval().unwrap();
+        }
+        let last_wait = original.backoff.state().expect("a last wait");
+        let resumed = resume(&original, &args);
+        assert_eq!(
+            resumed.backoff.state().map(|state| state.as_millis()),
+            Some(last_wait.as_millis())
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+use std::time::Instant;
+
+/// Where a `LoopManager` gets the time from, so that it can be driven by
+/// another clock than real time, such as the `MockClock` the tests use
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic clock, which is what retrying normally runs by
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when it is told to, for testing
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct MockClock {
+    start: Instant,
+    offset: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub(crate) fn new() -> MockClock {
+        MockClock {
+            start: Instant::now(),
+            offset: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    /// Move the time on by `by`
+    pub(crate) fn advance(&self, by: Duration) {
+        let mut offset = self.offset.lock().unwrap_or_else(|e| e.into_inner());
+        *offset += by;
+    }
+}
+
+#[cfg(test)]
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,440 @@
+use quicli::prelude::structopt::clap::ArgMatches;
+use quicli::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::iter;
+use std::path::Path;
+use toml;
+
+/// A value which is passed on as written, such as a duration, which may be
+/// given as a number of seconds or a string with a unit
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Integer(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Text(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Where the JSON report goes: `true` for stdout, or a file
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ReportJson {
+    Stdout(bool),
+    File(String),
+}
+
+/// The contents of a config file, whose keys are the names of the command
+/// line options with underscores, e.g. `maximum_iterations = 5`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    command_file: Option<String>,
+    and: Option<Vec<String>>,
+    recheck_passed: Option<bool>,
+    parallel: Option<bool>,
+    max_parallel: Option<Value>,
+    report_json: Option<ReportJson>,
+    webhook: Option<String>,
+    notify: Option<bool>,
+    pid_file: Option<String>,
+    heartbeat_file: Option<String>,
+    heartbeat_interval: Option<Value>,
+    lock_file: Option<String>,
+    once: Option<bool>,
+    dry_run: Option<bool>,
+    summary: Option<bool>,
+    success_message: Option<String>,
+    fail_message: Option<String>,
+    buffer_stdin: Option<bool>,
+    shell: Option<bool>,
+    cwd: Option<String>,
+    env: Option<BTreeMap<String, String>>,
+    env_file: Option<String>,
+    env_clear: Option<bool>,
+    env_keep: Option<Vec<String>>,
+    no_inject_env: Option<bool>,
+    echo: Option<bool>,
+    dedup_output: Option<bool>,
+    diff_output: Option<bool>,
+    no_inherit_stdout_on_retry: Option<bool>,
+    show_last_output: Option<bool>,
+    tail_on_failure: Option<Value>,
+    quiet: Option<bool>,
+    quiet_success: Option<bool>,
+    quiet_failure: Option<bool>,
+    tee: Option<String>,
+    max_capture: Option<Value>,
+    timestamps: Option<bool>,
+    progress: Option<bool>,
+    spinner: Option<bool>,
+    report_interval: Option<Value>,
+    json_logs: Option<bool>,
+    syslog: Option<bool>,
+    syslog_facility: Option<String>,
+    syslog_tag: Option<String>,
+    no_color: Option<bool>,
+    log_file: Option<String>,
+    plan_file: Option<String>,
+    timeout: Option<Value>,
+    budget: Option<Value>,
+    deadline: Option<String>,
+    attempt_timeout: Option<Value>,
+    idle_timeout: Option<Value>,
+    kill_grace: Option<Value>,
+    warn_slow: Option<Value>,
+    nice: Option<Value>,
+    die_with_parent: Option<bool>,
+    max_memory: Option<Value>,
+    retry_spawn_errors: Option<bool>,
+    timeout_aborts: Option<bool>,
+    delay: Option<Value>,
+    interval: Option<Value>,
+    first_interval: Option<Value>,
+    initial_interval: Option<Value>,
+    multiplier: Option<Value>,
+    schedule: Option<Vec<Value>>,
+    backoff_from_stdout: Option<String>,
+    interval_by_code: Option<BTreeMap<String, Value>>,
+    max_interval: Option<Value>,
+    jitter: Option<Value>,
+    seed: Option<Value>,
+    jitter_mode: Option<String>,
+    retries: Option<Value>,
+    maximum_iterations: Option<Value>,
+    total_iterations_include_first: Option<bool>,
+    start_iteration: Option<Value>,
+    checkpoint: Option<String>,
+    resume: Option<bool>,
+    count_downtime: Option<bool>,
+    max_rate: Option<Value>,
+    rate: Option<Value>,
+    burst: Option<Value>,
+    max_failures: Option<Value>,
+    max_consecutive_failures: Option<Value>,
+    grace: Option<Value>,
+    consecutive_successes: Option<Value>,
+    stability_window: Option<Value>,
+    watch: Option<bool>,
+    reset_backoff_after: Option<Value>,
+    adaptive_poll: Option<bool>,
+    success_codes: Option<Vec<i32>>,
+    stable_for: Option<Value>,
+    check: Option<String>,
+    until_stdout_matches: Option<String>,
+    until_stdout_not_matches: Option<String>,
+    until_line: Option<String>,
+    until_stderr_matches: Option<String>,
+    until_stderr_empty: Option<bool>,
+    until_stderr_nonempty: Option<bool>,
+    keep_stderr_whitespace: Option<bool>,
+    until_file_exists: Option<String>,
+    success_sentinel: Option<String>,
+    keep_sentinel: Option<bool>,
+    until_tcp: Option<String>,
+    until_http: Option<String>,
+    expect_status: Option<Vec<u16>>,
+    expect_body: Option<String>,
+    require_both: Option<bool>,
+    match_mode: Option<String>,
+    abort_codes: Option<Vec<i32>>,
+    exit_code_map: Option<BTreeMap<String, String>>,
+    signal_aborts: Option<bool>,
+    on_first_failure: Option<String>,
+    on_retry: Option<String>,
+    on_timeout: Option<String>,
+    on_success: Option<String>,
+    on_success_required: Option<bool>,
+    invert: Option<bool>,
+    backoff: Option<String>,
+    backoff_base: Option<Value>,
+}
+
+/// Pairs of options which set the same thing, and so conflict, so that giving
+/// either on the command line overrides both in a config file
+const ALIASES: &[(&str, &str)] = &[
+    ("retries", "maximum_iterations"),
+    ("retries", "total_iterations_include_first"),
+    ("interval", "initial_interval"),
+    ("multiplier", "backoff"),
+    ("multiplier", "backoff_base"),
+    ("schedule", "backoff"),
+    ("schedule", "multiplier"),
+];
+
+/// Collects the command line arguments equivalent to a config file, skipping
+/// any option which was given on the real command line
+struct Arguments<'a> {
+    matches: &'a ArgMatches<'a>,
+    args: Vec<String>,
+}
+
+impl<'a> Arguments<'a> {
+    fn overridden(&self, name: &str) -> bool {
+        let aliases = ALIASES.iter().filter_map(|&(a, b)| match name {
+            _ if a == name => Some(b),
+            _ if b == name => Some(a),
+            _ => None,
+        });
+        iter::once(name)
+            .chain(aliases)
+            .any(|name| self.matches.occurrences_of(name) > 0)
+    }
+
+    fn flag(&mut self, name: &str, value: Option<bool>) {
+        if value == Some(true) && !self.overridden(name) {
+            self.args.push(long(name));
+        }
+    }
+
+    fn value<T: fmt::Display>(&mut self, name: &str, value: Option<T>) {
+        self.values(name, value.into_iter())
+    }
+
+    fn values<T, I>(&mut self, name: &str, values: I)
+    where
+        T: fmt::Display,
+        I: Iterator<Item = T>,
+    {
+        if self.overridden(name) {
+            return;
+        }
+        for value in values {
+            // Attached with =, so values starting with - aren't taken as
+            // options
+            self.args.push(format!("{}={}", long(name), value));
+        }
+    }
+}
+
+/// The long option for the argument `name`
+fn long(name: &str) -> String {
+    match name {
+        // Repeated options are named in the singular on the command line
+        "success_codes" => String::from("--success-code"),
+        "abort_codes" => String::from("--abort-code"),
+        _ => format!("--{}", name.replace('_', "-")),
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format_err!("Could not read config file {}: {}", path.display(), e))?;
+        toml::from_str(&text)
+            .map_err(|e| format_err!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    /// The command line arguments which set the options in this config,
+    /// leaving out those already set in `matches`, so that command line
+    /// arguments take precedence
+    pub fn args(self, matches: &ArgMatches) -> Vec<String> {
+        let mut a = Arguments {
+            matches,
+            args: Vec::new(),
+        };
+        a.value("command_file", self.command_file);
+        a.values("and", self.and.unwrap_or_default().into_iter());
+        a.flag("recheck_passed", self.recheck_passed);
+        a.flag("parallel", self.parallel);
+        a.value("max_parallel", self.max_parallel);
+        match self.report_json {
+            Some(_) if a.overridden("report_json") => (),
+            Some(ReportJson::Stdout(true)) => a.args.push(long("report_json")),
+            Some(ReportJson::File(path)) => a.args.push(format!("--report-json={}", path)),
+            Some(ReportJson::Stdout(false)) | None => (),
+        }
+        a.value("webhook", self.webhook);
+        a.flag("notify", self.notify);
+        a.value("pid_file", self.pid_file);
+        a.value("heartbeat_file", self.heartbeat_file);
+        a.value("heartbeat_interval", self.heartbeat_interval);
+        a.value("lock_file", self.lock_file);
+        a.flag("once", self.once);
+        a.flag("dry_run", self.dry_run);
+        a.flag("summary", self.summary);
+        a.value("success_message", self.success_message);
+        a.value("fail_message", self.fail_message);
+        a.flag("buffer_stdin", self.buffer_stdin);
+        a.flag("shell", self.shell);
+        a.value("cwd", self.cwd);
+        a.values(
+            "env",
+            self.env
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(k, v)| format!("{}={}", k, v)),
+        );
+        a.value("env_file", self.env_file);
+        a.flag("env_clear", self.env_clear);
+        a.values("env_keep", self.env_keep.unwrap_or_default().into_iter());
+        a.flag("no_inject_env", self.no_inject_env);
+        a.flag("echo", self.echo);
+        a.flag("dedup_output", self.dedup_output);
+        a.flag("diff_output", self.diff_output);
+        a.flag(
+            "no_inherit_stdout_on_retry",
+            self.no_inherit_stdout_on_retry,
+        );
+        a.flag("show_last_output", self.show_last_output);
+        a.value("tail_on_failure", self.tail_on_failure);
+        a.flag("quiet", self.quiet);
+        a.flag("quiet_success", self.quiet_success);
+        a.flag("quiet_failure", self.quiet_failure);
+        a.value("tee", self.tee);
+        a.value("max_capture", self.max_capture);
+        a.flag("timestamps", self.timestamps);
+        a.flag("progress", self.progress);
+        a.flag("spinner", self.spinner);
+        a.value("report_interval", self.report_interval);
+        a.flag("json_logs", self.json_logs);
+        a.flag("syslog", self.syslog);
+        a.value("syslog_facility", self.syslog_facility);
+        a.value("syslog_tag", self.syslog_tag);
+        a.flag("no_color", self.no_color);
+        a.value("log_file", self.log_file);
+        a.value("plan_file", self.plan_file);
+        a.value("timeout", self.timeout);
+        a.value("budget", self.budget);
+        a.value("deadline", self.deadline);
+        a.value("attempt_timeout", self.attempt_timeout);
+        a.value("idle_timeout", self.idle_timeout);
+        a.value("kill_grace", self.kill_grace);
+        a.value("warn_slow", self.warn_slow);
+        a.value("nice", self.nice);
+        a.flag("die_with_parent", self.die_with_parent);
+        a.value("max_memory", self.max_memory);
+        a.flag("retry_spawn_errors", self.retry_spawn_errors);
+        a.flag("timeout_aborts", self.timeout_aborts);
+        a.value("delay", self.delay);
+        a.value("interval", self.interval);
+        a.value("first_interval", self.first_interval);
+        a.value("initial_interval", self.initial_interval);
+        a.value("multiplier", self.multiplier);
+        a.values("schedule", self.schedule.unwrap_or_default().into_iter());
+        a.value("backoff_from_stdout", self.backoff_from_stdout);
+        a.values(
+            "interval_by_code",
+            self.interval_by_code
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(rc, wait)| format!("{}={}", rc, wait)),
+        );
+        a.value("max_interval", self.max_interval);
+        a.value("jitter", self.jitter);
+        a.value("seed", self.seed);
+        a.value("jitter_mode", self.jitter_mode);
+        a.value("retries", self.retries);
+        a.value("maximum_iterations", self.maximum_iterations);
+        a.value(
+            "total_iterations_include_first",
+            self.total_iterations_include_first,
+        );
+        a.value("start_iteration", self.start_iteration);
+        a.value("checkpoint", self.checkpoint);
+        a.flag("resume", self.resume);
+        a.flag("count_downtime", self.count_downtime);
+        a.value("max_rate", self.max_rate);
+        a.value("rate", self.rate);
+        a.value("burst", self.burst);
+        a.value("max_failures", self.max_failures);
+        a.value("max_consecutive_failures", self.max_consecutive_failures);
+        a.value("grace", self.grace);
+        a.value("consecutive_successes", self.consecutive_successes);
+        a.value("stability_window", self.stability_window);
+        a.flag("watch", self.watch);
+        a.value("reset_backoff_after", self.reset_backoff_after);
+        a.flag("adaptive_poll", self.adaptive_poll);
+        a.values(
+            "success_codes",
+            self.success_codes.unwrap_or_default().into_iter(),
+        );
+        a.value("stable_for", self.stable_for);
+        a.value("check", self.check);
+        a.value("until_stdout_matches", self.until_stdout_matches);
+        a.value("until_stdout_not_matches", self.until_stdout_not_matches);
+        a.value("until_line", self.until_line);
+        a.value("until_stderr_matches", self.until_stderr_matches);
+        a.flag("until_stderr_empty", self.until_stderr_empty);
+        a.flag("until_stderr_nonempty", self.until_stderr_nonempty);
+        a.flag("keep_stderr_whitespace", self.keep_stderr_whitespace);
+        a.value("until_file_exists", self.until_file_exists);
+        a.value("success_sentinel", self.success_sentinel);
+        a.flag("keep_sentinel", self.keep_sentinel);
+        a.value("until_tcp", self.until_tcp);
+        a.value("until_http", self.until_http);
+        a.values(
+            "expect_status",
+            self.expect_status.unwrap_or_default().into_iter(),
+        );
+        a.value("expect_body", self.expect_body);
+        a.flag("require_both", self.require_both);
+        a.value("match_mode", self.match_mode);
+        a.values(
+            "abort_codes",
+            self.abort_codes.unwrap_or_default().into_iter(),
+        );
+        a.values(
+            "exit_code_map",
+            self.exit_code_map
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(rc, class)| format!("{}={}", rc, class)),
+        );
+        a.flag("signal_aborts", self.signal_aborts);
+        a.value("on_first_failure", self.on_first_failure);
+        a.value("on_retry", self.on_retry);
+        a.value("on_timeout", self.on_timeout);
+        a.value("on_success", self.on_success);
+        a.flag("on_success_required", self.on_success_required);
+        a.flag("invert", self.invert);
+        a.value("backoff", self.backoff);
+        a.value("backoff_base", self.backoff_base);
+        a.args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quicli::prelude::*;
+    use toml;
+
+    use super::Config;
+    use RetryCli;
+
+    /// The arguments a config file `text` adds to the command line `args`
+    fn args(text: &str, args: &[&str]) -> Vec<String> {
+        let config: Config = toml::from_str(text).unwrap();
+        let mut cli = vec!["retry"];
+        cli.extend(args);
+        cli.extend(&["--", "true"]);
+        config.args(&RetryCli::clap().get_matches_from(cli))
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_config_file() {
+        assert_eq!(args("timeout = 5", &[]), ["--timeout=5"]);
+        assert!(args("timeout = 5", &["--timeout", "1"]).is_empty());
+    }
+
+    #[test]
+    fn the_command_line_overrides_aliases_in_the_config_file() {
+        assert!(args("maximum_iterations = 5", &["--retries", "1"]).is_empty());
+        assert!(args("retries = 5", &["-m", "1"]).is_empty());
+        assert!(args("interval = 5", &["--initial-interval", "1"]).is_empty());
+        assert!(args("initial_interval = 5", &["-i", "1"]).is_empty());
+        assert!(args("backoff = \"linear\"", &["--multiplier", "1.5"]).is_empty());
+        assert!(args("multiplier = 1.5", &["--backoff", "linear"]).is_empty());
+    }
+}
@@ -0,0 +1,67 @@
+/// Largest number of pairs of lines compared, beyond which the lines which
+/// differ are all shown as changed rather than matched up
+const MAX_COMPARISONS: usize = 1 << 22;
+
+/// A line which differs between two outputs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change<'a> {
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// The lines which differ between `old` and `new`, in order, found from the
+/// longest common subsequence of their lines
+pub fn changed_lines<'a>(old: &'a str, new: &'a str) -> Vec<Change<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // Lines which are the same at either end needn't be compared
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let (old, new) = (&old[prefix..], &new[prefix..]);
+    let suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old, new) = (&old[..old.len() - suffix], &new[..new.len() - suffix]);
+
+    if old.len().saturating_mul(new.len()) > MAX_COMPARISONS {
+        return old
+            .iter()
+            .map(|line| Change::Removed(line))
+            .chain(new.iter().map(|line| Change::Added(line)))
+            .collect();
+    }
+
+    // common[i][j] is the length of the longest common subsequence of
+    // old[i..] and new[j..]
+    let width = new.len() + 1;
+    let mut common = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i * width + j] = if old[i] == new[j] {
+                common[(i + 1) * width + j + 1] + 1
+            } else {
+                common[(i + 1) * width + j].max(common[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if common[(i + 1) * width + j] >= common[i * width + j + 1] {
+            changes.push(Change::Removed(old[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Added(new[j]));
+            j += 1;
+        }
+    }
+    changes.extend(old[i..].iter().map(|line| Change::Removed(line)));
+    changes.extend(new[j..].iter().map(|line| Change::Added(line)));
+    changes
+}
@@ -0,0 +1,70 @@
+use std::env;
+use std::io::{self, IsTerminal};
+use std::time::Duration;
+
+/// A duration for people to read, such as 250ms, 3.0s or 1m2s
+pub fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if duration < Duration::from_secs(1) {
+        format!("{}ms", duration.as_millis())
+    } else if secs < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if secs < 60 * 60 {
+        format!("{}m{}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{}m{}s", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
+
+/// Colours for how things are going
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    Green,
+    Red,
+}
+
+/// Whether to colour what we write to stderr: not if asked not to, either
+/// with `no_color` or the NO_COLOR environment variable, or if stderr isn't a
+/// terminal
+pub fn color_enabled(no_color: bool) -> bool {
+    color_enabled_on(no_color, &io::stderr())
+}
+
+/// Whether to colour what we write to `stream`, likewise
+pub fn color_enabled_on<T: IsTerminal>(no_color: bool, stream: &T) -> bool {
+    !no_color && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && stream.is_terminal()
+}
+
+/// `text` in `color`, if colouring is `enabled`
+pub fn paint(text: &str, color: Color, enabled: bool) -> String {
+    if !enabled {
+        return text.to_string();
+    }
+    let code = match color {
+        Color::Green => 32,
+        Color::Red => 31,
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+/// Frames of the spinner shown while waiting between attempts
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+/// An animation on stderr counting down to the next attempt
+#[derive(Debug, Default)]
+pub(crate) struct Spinner {
+    frame: usize,
+}
+
+impl Spinner {
+    /// Redraw the spinner, showing `remaining` until the next attempt
+    pub(crate) fn draw(&mut self, remaining: Duration) {
+        // Padded, so a shorter line covers all of a longer one
+        eprint!(
+            "\r{} Next attempt in {:<10}",
+            SPINNER[self.frame % SPINNER.len()],
+            human_duration(remaining)
+        );
+        self.frame += 1;
+    }
+}
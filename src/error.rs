@@ -0,0 +1,93 @@
+use quicli::prelude::*;
+use std::fmt;
+
+/// A number of retries, which reads as e.g. "1 retry" or "3 retries"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retries(pub usize);
+
+impl fmt::Display for Retries {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            1 => write!(f, "1 retry"),
+            n => write!(f, "{} retries", n),
+        }
+    }
+}
+
+/// Errors for retry
+#[derive(Debug, Fail)]
+pub enum RetryError {
+    #[fail(display = "Retrying command did not succeed due to timeout")]
+    Timeout(),
+    #[fail(display = "Gave up after 1 initial attempt and {}", _0)]
+    MaximumIterations(Retries),
+    #[fail(display = "Retrying command failed {} times", _0)]
+    MaxFailures(usize),
+    #[fail(display = "Retrying command failed {} times in a row", _0)]
+    ConsecutiveFailures(usize),
+    #[fail(display = "Retrying command did not succeed before the deadline")]
+    DeadlineExceeded(),
+    #[fail(display = "Command exited with abort code {}", _0)]
+    Aborted(i32),
+    #[fail(display = "Attempt {} exceeded the attempt timeout", _0)]
+    AttemptTimedOut(usize),
+    #[fail(display = "Command was killed by signal {}", _0)]
+    Signalled(i32),
+    #[fail(display = "Command did not keep succeeding for the stability window")]
+    Unstable(),
+    #[fail(display = "Retrying was interrupted")]
+    Interrupted(),
+    #[fail(display = "Command {} not found", _0)]
+    NotFound(String),
+    #[fail(display = "Could not run command {}: {}", _0, _1)]
+    CannotRun(String, String),
+}
+
+impl RetryError {
+    /// A short, stable description of why retrying stopped
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            RetryError::Timeout() => "timeout",
+            RetryError::MaximumIterations(_) => "max-iterations",
+            RetryError::MaxFailures(_) => "max-failures",
+            RetryError::ConsecutiveFailures(_) => "consecutive-failures",
+            RetryError::DeadlineExceeded() => "deadline",
+            RetryError::Aborted(_) => "aborted",
+            RetryError::AttemptTimedOut(_) => "attempt-timeout",
+            RetryError::Signalled(_) => "signalled",
+            RetryError::Unstable() => "unstable",
+            RetryError::Interrupted() => "interrupted",
+            RetryError::NotFound(_) => "not-found",
+            RetryError::CannotRun(_, _) => "cannot-run",
+        }
+    }
+
+    /// The exit status retry stops with:
+    ///
+    /// - 124 when time runs out, including a single attempt's timeout, or
+    ///   the command never stays successful for the stability window
+    /// - 125 when the attempts or failures run out
+    /// - 130 when interrupted
+    /// - 128 + the signal number when the command is killed by a signal
+    /// - 127 when the command can't be found, and 126 when it can't be run
+    /// - the command's own exit code when it is an abort code
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            RetryError::Timeout()
+            | RetryError::DeadlineExceeded()
+            | RetryError::AttemptTimedOut(_)
+            | RetryError::Unstable() => 124,
+            RetryError::MaximumIterations(_)
+            | RetryError::MaxFailures(_)
+            | RetryError::ConsecutiveFailures(_) => 125,
+            RetryError::Interrupted() => 130,
+            RetryError::Signalled(signal) => 128 + signal,
+            RetryError::NotFound(_) => 127,
+            RetryError::CannotRun(_, _) => 126,
+            // An abort code of 0 is possible with --invert, but mustn't look
+            // like success
+            RetryError::Aborted(0) => 1,
+            RetryError::Aborted(rc) => rc,
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use chrono::{SecondsFormat, Utc};
+use quicli::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// A file which is written with the time every so often, from a thread of
+/// its own so that it carries on through long attempts and waits alike, to
+/// show that we are still alive. It is removed when this is dropped.
+pub struct Heartbeat {
+    path: PathBuf,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Write the time to `path`, replacing it in one go, so that whatever reads
+/// it can't find it empty or half written
+fn beat(path: &Path) -> Result<()> {
+    let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    fs::write(&partial, now + "\n")
+        .and_then(|()| fs::rename(&partial, path))
+        .map_err(|e| format_err!("Could not write heartbeat file {}: {}", path.display(), e))
+}
+
+impl Heartbeat {
+    /// Write `path` now, and every `interval` from now on
+    pub fn start(path: &Path, interval: Duration) -> Result<Heartbeat> {
+        beat(path)?;
+        let (stop, stopped) = mpsc::channel();
+        let beating = path.to_path_buf();
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(e) = beat(&beating) {
+                        eprintln!("warning: {}", e);
+                    }
+                }
+                _ => return,
+            }
+        });
+        Ok(Heartbeat {
+            path: path.to_path_buf(),
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        // Hanging up stops the thread, which is waited for so that it can't
+        // write the file again after it is removed
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!(
+                "warning: could not remove heartbeat file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use quicli::prelude::*;
+use std::process::ExitStatus;
+
+use attempt::shell_command;
+
+/// Run a hook command through the shell, with `env` added to its
+/// environment, and wait for it to finish
+pub fn run_hook(name: &str, hook: &str, env: &[(&str, String)]) -> Result<ExitStatus> {
+    debug!("Running {} hook: {}", name, hook);
+    let status = shell_command(hook)
+        .envs(env.iter().map(|&(k, ref v)| (k, v)))
+        .status()?;
+    match status.code() {
+        Some(0) => debug!("{} hook succeeded", name),
+        Some(rc) => warn!("{} hook exited with code {}", name, rc),
+        None => warn!("{} hook was killed by a signal", name),
+    }
+    Ok(status)
+}
+
+/// Run a check command through the shell, with `env` added to its
+/// environment, returning whether it exited with 0
+pub fn run_check(check: &str, env: &[(&str, String)]) -> Result<bool> {
+    debug!("Running check: {}", check);
+    let status = shell_command(check)
+        .envs(env.iter().map(|&(k, ref v)| (k, v)))
+        .status()?;
+    debug!("Check finished with {}", status);
+    Ok(status.success())
+}
@@ -0,0 +1,861 @@
+//! Retry runs commands in a loop until they succeed
+//!
+//! The command line tool is a thin wrapper around this library, which can
+//! also be used to retry commands, or anything else implementing `Task`,
+//! from other programs.
+
+// failure_derive generates its impls inside a named const
+#![allow(non_local_definitions)]
+
+extern crate chrono;
+extern crate ctrlc;
+extern crate failure;
+#[cfg(unix)]
+extern crate libc;
+extern crate quicli;
+extern crate rand;
+extern crate regex;
+extern crate serde;
+extern crate serde_json;
+extern crate ureq;
+
+mod attempt;
+mod attempt_log;
+mod backoff;
+mod checkpoint;
+mod clock;
+mod diff;
+mod display;
+mod error;
+mod hooks;
+mod loop_manager;
+mod options;
+mod plan_file;
+mod process_group;
+mod report;
+mod signals;
+mod syslog;
+
+pub use attempt::{run_attempt, AllOf, Attempt, Capture, CommandTask, Echo, Outcome, Tail, Task};
+pub use backoff::{
+    Backoff, BackoffClone, BackoffStrategy, Constant, DecorrelatedJitter, Exponential, Fibonacci,
+    JitterMode, Linear, Schedule,
+};
+pub use clock::{Clock, MonotonicClock};
+pub use display::{color_enabled, human_duration, paint, Color};
+pub use error::{Retries, RetryError};
+pub use hooks::{run_check, run_hook};
+pub use loop_manager::LoopManager;
+pub use options::{
+    parse_code_interval, parse_count, parse_deadline, parse_directory, parse_duration,
+    parse_env_var, parse_exit_class, parse_fraction, parse_rate, parse_size, EnvFile, ExitClass,
+    MatchMode, RetryOptions, Seconds,
+};
+pub use report::Report;
+pub use signals::{die_with_parent, handle_signals, interrupted};
+pub use syslog::SyslogFacility;
+
+use quicli::prelude::*;
+use regex::Regex;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+
+use attempt_log::AttemptLog;
+use checkpoint::Checkpoint;
+use display::Spinner;
+use plan_file::PlanFile;
+use report::AttemptEvent;
+
+/// How long to wait for a TCP connection, without an attempt timeout
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for an HTTP response, without an attempt timeout
+const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether captured output matches `pattern`
+fn output_matches(pattern: &Regex, output: &Option<Vec<u8>>) -> bool {
+    output
+        .as_ref()
+        .is_some_and(|out| pattern.is_match(&String::from_utf8_lossy(out)))
+}
+
+/// Whether any line of captured output matches `pattern`
+fn line_matches(pattern: &Regex, output: &Option<Vec<u8>>) -> bool {
+    output.as_ref().is_some_and(|out| {
+        String::from_utf8_lossy(out)
+            .lines()
+            .any(|line| pattern.is_match(line))
+    })
+}
+
+/// Whether captured output is empty, or only whitespace if `trim`
+fn output_empty(output: &Option<Vec<u8>>, trim: bool) -> bool {
+    output
+        .as_ref()
+        .is_none_or(|out| out.is_empty() || trim && out.iter().all(u8::is_ascii_whitespace))
+}
+
+/// Whether something is accepting TCP connections at `address` (host:port),
+/// trying each address it resolves to until one connects within `timeout`
+fn tcp_accepting(address: &str, timeout: Duration) -> bool {
+    let addrs = match address.to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            // The name may not resolve until the service is up
+            debug!("Could not resolve {}: {}", address, e);
+            return false;
+        }
+    };
+    // The connection is closed again as soon as it is dropped
+    addrs
+        .into_iter()
+        .any(|addr| TcpStream::connect_timeout(&addr, timeout).is_ok())
+}
+
+/// The status and body of the response to a GET of `url`, if there is one
+/// within `timeout`
+fn http_get(url: &str, timeout: Duration) -> Option<(u16, String)> {
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+    let response = match agent.get(url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => {
+            // Most likely the service isn't listening yet
+            debug!("No response from {}: {}", url, e);
+            return None;
+        }
+    };
+    let status = response.status();
+    let body = response.into_string().unwrap_or_else(|e| {
+        debug!("Could not read the response from {}: {}", url, e);
+        String::new()
+    });
+    Some((status, body))
+}
+
+/// Whether a GET of `url` gets the response expected by `options`, noting its
+/// status in `loop_manager`
+fn http_ready(url: &str, options: &RetryOptions, loop_manager: &mut LoopManager) -> bool {
+    let timeout = options
+        .attempt_timeout
+        .map_or(DEFAULT_HTTP_TIMEOUT, Seconds::duration);
+    let (status, body) = match http_get(url, timeout) {
+        Some(response) => response,
+        None => {
+            loop_manager.last_http_status = None;
+            return false;
+        }
+    };
+    loop_manager.last_http_status = Some(status);
+    let status_ok = if options.expect_status.is_empty() {
+        (200..300).contains(&status)
+    } else {
+        options.expect_status.contains(&status)
+    };
+    if !status_ok {
+        debug!("Got HTTP status {} from {}", status, url);
+        return false;
+    }
+    options
+        .expect_body
+        .as_ref()
+        .is_none_or(|pattern| pattern.is_match(&body))
+}
+
+/// The wait which `pattern`'s first capture group gives in captured output
+fn output_hint(pattern: &Regex, output: &Option<Vec<u8>>) -> Option<Seconds> {
+    let output = String::from_utf8_lossy(output.as_ref()?);
+    let hint = pattern.captures(&output)?.get(1)?.as_str();
+    match hint.parse() {
+        Ok(seconds) => Some(seconds),
+        Err(e) => {
+            warn!("Ignoring backoff hint {}: {}", hint, e);
+            None
+        }
+    }
+}
+
+/// One of the things which decide whether an attempt succeeded
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Condition {
+    ExitCode,
+    StableFor(usize),
+    Check,
+    StdoutMatches,
+    StdoutNotMatches,
+    StderrMatches,
+    StderrEmpty,
+    LineMatched,
+    StderrNonEmpty,
+    FileExists,
+    Tcp,
+    Http,
+}
+
+impl Condition {
+    /// Whether the condition depends only on the attempt itself, such as its
+    /// exit code or output, so that each of several tasks can be judged by it
+    fn of_attempt(self) -> bool {
+        match self {
+            Condition::ExitCode
+            | Condition::StdoutMatches
+            | Condition::StdoutNotMatches
+            | Condition::StderrMatches
+            | Condition::StderrEmpty
+            | Condition::LineMatched
+            | Condition::StderrNonEmpty => true,
+            Condition::StableFor(_)
+            | Condition::Check
+            | Condition::FileExists
+            | Condition::Tcp
+            | Condition::Http => false,
+        }
+    }
+}
+
+/// Whether `condition`, which depends only on the attempt itself, held for
+/// `attempt`
+fn attempt_held(
+    condition: Condition,
+    options: &RetryOptions,
+    success_codes: &[i32],
+    attempt: &Attempt,
+) -> bool {
+    match condition {
+        Condition::ExitCode => attempt.code().is_some_and(|rc| success_codes.contains(&rc)),
+        Condition::StdoutMatches => options
+            .until_stdout_matches
+            .as_ref()
+            .is_some_and(|p| output_matches(p, &attempt.stdout)),
+        Condition::StdoutNotMatches => options
+            .until_stdout_not_matches
+            .as_ref()
+            .is_some_and(|p| !output_matches(p, &attempt.stdout)),
+        Condition::StderrMatches => options
+            .until_stderr_matches
+            .as_ref()
+            .is_some_and(|p| output_matches(p, &attempt.stderr)),
+        // The command may have exited just after printing the line, before it
+        // could be stopped
+        Condition::LineMatched => {
+            matches!(attempt.outcome, Outcome::Matched(_))
+                || options
+                    .until_line
+                    .as_ref()
+                    .is_some_and(|p| line_matches(p, &attempt.stdout))
+        }
+        Condition::StderrEmpty => output_empty(&attempt.stderr, !options.keep_stderr_whitespace),
+        Condition::StderrNonEmpty => {
+            !output_empty(&attempt.stderr, !options.keep_stderr_whitespace)
+        }
+        _ => unreachable!("{:?} doesn't depend on the attempt", condition),
+    }
+}
+
+/// Whether `attempt`, of one of several tasks attempted together, passed: the
+/// conditions among `conditions` which depend on the attempt itself are
+/// combined as --match-mode says, and inverted by --invert. The rest, such as
+/// --until-tcp, are left to decide the round as a whole.
+fn task_passed(
+    options: &RetryOptions,
+    conditions: &[Condition],
+    success_codes: &[i32],
+    attempt: &Attempt,
+) -> bool {
+    let mut held = conditions
+        .iter()
+        .filter(|condition| condition.of_attempt())
+        .map(|&condition| attempt_held(condition, options, success_codes, attempt))
+        .peekable();
+    if held.peek().is_none() {
+        return true;
+    }
+    let held = match options.match_mode {
+        MatchMode::All => held.all(|held| held),
+        MatchMode::Any => held.any(|held| held),
+    };
+    held != options.invert
+}
+
+/// The conditions `options` set, in the order they are checked. The exit code
+/// only counts if the task runs a command, and --check, --stable-for or an
+/// output pattern take its place, unless a pattern comes with --require-both.
+fn conditions(options: &RetryOptions, runs_command: bool) -> Vec<Condition> {
+    let mut conditions = Vec::new();
+    let patterns = [
+        (
+            options.until_stdout_matches.is_some(),
+            Condition::StdoutMatches,
+        ),
+        (
+            options.until_stdout_not_matches.is_some(),
+            Condition::StdoutNotMatches,
+        ),
+        (
+            options.until_stderr_matches.is_some(),
+            Condition::StderrMatches,
+        ),
+        (options.until_line.is_some(), Condition::LineMatched),
+        (options.until_stderr_empty, Condition::StderrEmpty),
+        (options.until_stderr_nonempty, Condition::StderrNonEmpty),
+    ];
+    let has_pattern = patterns.iter().any(|&(set, _)| set);
+    if let Some(n) = options.stable_for {
+        conditions.push(Condition::StableFor(n));
+    } else if options.check.is_some() {
+        conditions.push(Condition::Check);
+    } else {
+        conditions.extend(patterns.iter().filter(|&&(set, _)| set).map(|&(_, c)| c));
+        if runs_command && (!has_pattern || options.require_both) {
+            conditions.push(Condition::ExitCode);
+        }
+    }
+    if options.until_file_exists.is_some() {
+        conditions.push(Condition::FileExists);
+    }
+    if options.until_tcp.is_some() {
+        conditions.push(Condition::Tcp);
+    }
+    if options.until_http.is_some() {
+        conditions.push(Condition::Http);
+    }
+    conditions
+}
+
+/// An exit code as exposed to hooks, which is empty if the command was killed
+fn exit_code_env(code: Option<i32>) -> String {
+    code.map(|rc| rc.to_string()).unwrap_or_default()
+}
+
+/// Attempt `task` until it succeeds, or `loop_manager` gives up, returning
+/// the final attempt
+pub fn retry<T: Task>(
+    task: &mut T,
+    options: &RetryOptions,
+    loop_manager: &mut LoopManager,
+) -> Result<Attempt> {
+    if let (Some(path), true) = (options.checkpoint.as_ref(), options.resume) {
+        if let Some(checkpoint) = Checkpoint::load(path)? {
+            info!("Resuming from checkpoint {}", path.display());
+            checkpoint.restore(loop_manager, options.count_downtime);
+        }
+    }
+    let mut attempts = 0;
+    let result = attempt_until_done(task, options, loop_manager, &mut attempts);
+    if let (Some(hook), Err(e)) = (options.on_timeout.as_ref(), result.as_ref()) {
+        if let Some(&RetryError::Timeout()) = e.downcast_ref::<RetryError>() {
+            run_hook(
+                "on-timeout",
+                hook,
+                &[
+                    ("RETRY_ITERATION", attempts.to_string()),
+                    (
+                        "RETRY_ELAPSED_MS",
+                        loop_manager.elapsed()?.as_millis().to_string(),
+                    ),
+                ],
+            )?;
+        }
+    }
+    if let Some(ref path) = options.checkpoint {
+        // Kept if we were stopped part way, say by a shutdown, or something
+        // went wrong, so that retrying can carry on from it
+        let over = match result {
+            Ok(_) => true,
+            Err(ref e) => !matches!(
+                e.downcast_ref::<RetryError>(),
+                None | Some(RetryError::Interrupted())
+            ),
+        };
+        if over {
+            Checkpoint::remove(path)?;
+        }
+    }
+    result
+}
+
+/// The retrying itself, counting the attempts made in `attempts`
+fn attempt_until_done<T: Task>(
+    task: &mut T,
+    options: &RetryOptions,
+    loop_manager: &mut LoopManager,
+    attempts: &mut usize,
+) -> Result<Attempt> {
+    // Checked up front, so that conflicting exit codes are an error at once
+    options.exit_classes()?;
+    let success_codes = options.success_codes();
+    let abort_codes = options.abort_codes();
+    let conditions = conditions(options, task.runs_command());
+    let mut successes = 0;
+    let mut failed_before = false;
+    let mut previous_output = None;
+    let mut unchanged = 0;
+    let mut stable_since = None;
+    let mut healthy = false;
+    let mut log = match options.log_file {
+        Some(ref path) => Some(AttemptLog::open(path)?),
+        None => None,
+    };
+    let mut plan = match options.plan_file {
+        Some(ref path) => Some(PlanFile::open(path)?),
+        None => None,
+    };
+
+    if let Some(delay) = options.delay {
+        signals::sleep(delay.duration())?;
+    }
+
+    loop {
+        if signals::interrupted() {
+            return Err(RetryError::Interrupted().into());
+        }
+        if let Some(ref path) = options.success_sentinel {
+            if path.exists() {
+                info!("Found {}, so stopping", path.display());
+                if !options.keep_sentinel {
+                    if let Err(e) = fs::remove_file(path) {
+                        eprintln!("warning: could not remove {}: {}", path.display(), e);
+                    }
+                }
+                return Ok(Attempt::exited(ExitStatus::default()));
+            }
+        }
+        let throttle = loop_manager.start_attempt();
+        if throttle > Duration::from_secs(0) {
+            trace!("Waiting {:?} to keep within the maximum rate", throttle);
+            signals::sleep(throttle)?;
+        }
+        loop_manager.check_budget()?;
+
+        let started = Instant::now();
+        let started_at = loop_manager.elapsed()?;
+        let (attempt, judged) = task.attempt_judged(loop_manager, &|attempt| {
+            task_passed(options, &conditions, &success_codes, attempt)
+        })?;
+        *attempts += 1;
+        let ran_for = started.elapsed();
+        trace!(
+            "Attempt {} ran for {:?}, {:?} into retrying",
+            loop_manager.iteration + 1,
+            ran_for,
+            loop_manager.elapsed()?
+        );
+        if let Some(threshold) = options.warn_slow {
+            if ran_for > threshold.duration() {
+                warn!(
+                    "Attempt {} was slow, taking {}",
+                    loop_manager.iteration + 1,
+                    human_duration(ran_for)
+                );
+            }
+        }
+        let last_code = attempt.code();
+        if let Some(ref mut log) = log {
+            log.record(loop_manager.iteration + 1, ran_for, last_code)?;
+        }
+        if let Some(ref mut plan) = plan {
+            plan.attempt(
+                loop_manager.iteration + 1,
+                started_at,
+                ran_for,
+                last_code,
+                loop_manager.seed,
+            )?;
+        }
+        if options.json_logs {
+            AttemptEvent {
+                iteration: loop_manager.iteration + 1,
+                exit_code: last_code,
+                duration_ms: ran_for.as_millis() as u64,
+                elapsed_ms: loop_manager.elapsed()?.as_millis() as u64,
+            }
+            .log(options)?;
+        }
+        if let Outcome::TimedOut(_) = attempt.outcome {
+            if options.timeout_aborts {
+                info!(
+                    "Attempt {} timed out, giving up",
+                    loop_manager.iteration + 1
+                );
+                return Err(RetryError::AttemptTimedOut(loop_manager.iteration + 1).into());
+            }
+            info!(
+                "Attempt {} timed out, counting it as a failure",
+                loop_manager.iteration + 1
+            );
+        }
+        loop_manager.last_exit_code = last_code;
+        if options.show_last_output {
+            loop_manager.last_output = Some((attempt.stdout.clone(), attempt.stderr.clone()));
+        }
+        let changed = if options.stable_for.is_some() || options.adaptive_poll {
+            let output = (attempt.stdout.clone(), attempt.stderr.clone());
+            let changed = previous_output.as_ref() != Some(&output);
+            if changed {
+                unchanged = 1;
+            } else {
+                unchanged += 1;
+            }
+            previous_output = Some(output);
+            changed
+        } else {
+            false
+        };
+        // Checked in order, stopping once the outcome is settled, so that
+        // later conditions such as --until-http aren't tried needlessly
+        let all = options.match_mode == MatchMode::All;
+        let mut succeeded = all;
+        for &condition in &conditions {
+            let held = match condition {
+                // Each task's attempt has been judged by these already, and
+                // inverted, which inverting the outcome below undoes
+                _ if condition.of_attempt() => match judged {
+                    Some(passed) => passed != options.invert,
+                    None => attempt_held(condition, options, &success_codes, &attempt),
+                },
+                Condition::StableFor(n) => unchanged >= n,
+                Condition::Check => run_check(
+                    options.check.as_ref().expect("--check is set"),
+                    &[
+                        ("RETRY_ITERATION", (loop_manager.iteration + 1).to_string()),
+                        ("RETRY_EXIT_CODE", exit_code_env(last_code)),
+                    ],
+                )?,
+                // Checked afresh every time, as the file may come and go
+                Condition::FileExists => options
+                    .until_file_exists
+                    .as_ref()
+                    .is_some_and(|path| path.exists()),
+                Condition::Tcp => options.until_tcp.as_ref().is_some_and(|address| {
+                    let timeout = options
+                        .attempt_timeout
+                        .map_or(DEFAULT_CONNECT_TIMEOUT, Seconds::duration);
+                    tcp_accepting(address, timeout)
+                }),
+                Condition::Http => match options.until_http {
+                    Some(ref url) => http_ready(url, options, loop_manager),
+                    None => false,
+                },
+                _ => unreachable!("{:?} depends on the attempt", condition),
+            };
+            if held != all {
+                succeeded = held;
+                break;
+            }
+        }
+        if succeeded != options.invert {
+            successes += 1;
+            loop_manager.record_success();
+            if stable_since.is_none() {
+                stable_since = Some(loop_manager.elapsed()?);
+            }
+        } else {
+            successes = 0;
+            stable_since = None;
+        }
+        let stable = match (options.stability_window, stable_since) {
+            (Some(window), Some(since)) => {
+                loop_manager.stabilising = true;
+                loop_manager.elapsed()? - since >= window.duration()
+            }
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        if successes >= options.consecutive_successes && stable {
+            let recovered = !healthy;
+            healthy = true;
+            if let (Some(ref hook), true) = (&options.on_success, recovered) {
+                let status = run_hook(
+                    "on-success",
+                    hook,
+                    &[
+                        ("RETRY_ITERATION", (loop_manager.iteration + 1).to_string()),
+                        ("RETRY_EXIT_CODE", exit_code_env(last_code)),
+                    ],
+                )?;
+                if options.on_success_required && !status.success() {
+                    bail!("on-success hook failed: {}", status);
+                }
+            }
+            if !options.watch {
+                return Ok(attempt);
+            }
+            // Carry on watching, as if starting again from here
+            loop_manager.restart_limits()?;
+            loop_manager.step()?;
+            loop_manager.reset_backoff()?;
+            failed_before = false;
+            pause(options, loop_manager, &mut plan)?;
+            continue;
+        }
+        if signals::interrupted() {
+            return Err(RetryError::Interrupted().into());
+        }
+
+        if successes > 0 {
+            // Confirming a success still uses up time and attempts
+            loop_manager.step()?;
+            if options.reset_backoff_after == Some(successes) {
+                debug!("Resetting backoff after {} successes", successes);
+                loop_manager.reset_backoff()?;
+                failed_before = false;
+            }
+            debug!("Success {} of {}", successes, options.consecutive_successes);
+            pause(options, loop_manager, &mut plan)?;
+            continue;
+        }
+
+        if let Some(rc) = last_code {
+            if abort_codes.contains(&rc) {
+                return Err(RetryError::Aborted(rc).into());
+            }
+        }
+        if options.signal_aborts {
+            if let Some(signal) = attempt.signal() {
+                return Err(RetryError::Signalled(signal).into());
+            }
+        }
+
+        if let Some(ref hook) = options.on_first_failure {
+            if !failed_before {
+                run_hook(
+                    "on-first-failure",
+                    hook,
+                    &[
+                        ("RETRY_ITERATION", (loop_manager.iteration + 1).to_string()),
+                        ("RETRY_EXIT_CODE", exit_code_env(last_code)),
+                    ],
+                )?;
+            }
+        }
+        failed_before = true;
+        healthy = false;
+
+        loop_manager.record_failure()?;
+        loop_manager.step()?;
+        if options.adaptive_poll && changed {
+            debug!("Output changed, going back to the first interval");
+            loop_manager.reset_backoff()?;
+        }
+        let code_hint = last_code.and_then(|rc| {
+            options
+                .interval_by_code
+                .iter()
+                .find(|&&(code, _)| code == rc)
+                .map(|&(_, wait)| wait)
+        });
+        let stdout_hint = options
+            .backoff_from_stdout
+            .as_ref()
+            .and_then(|pattern| output_hint(pattern, &attempt.stdout));
+        loop_manager.hinted_interval = stdout_hint.or(code_hint).map(Seconds::duration);
+
+        if let Some(ref hook) = options.on_retry {
+            run_hook(
+                "on-retry",
+                hook,
+                &[("RETRY_ITERATION", loop_manager.iteration.to_string())],
+            )?;
+        }
+
+        debug!("Loop manager status: {:?}", loop_manager.status()?);
+        if loop_manager.report_due() {
+            eprintln!("{}", loop_manager.status()?);
+        }
+        if options.progress {
+            // Overwrite the previous progress line rather than scrolling
+            eprint!(
+                "\r{}",
+                paint(
+                    &loop_manager.status()?,
+                    Color::Red,
+                    color_enabled(options.no_color)
+                )
+            );
+        }
+
+        pause(options, loop_manager, &mut plan)?;
+    }
+}
+
+/// Sleep until the next attempt is due
+fn pause(
+    options: &RetryOptions,
+    loop_manager: &mut LoopManager,
+    plan: &mut Option<PlanFile>,
+) -> Result<()> {
+    let interval = loop_manager.interval()?;
+    if let Some(ref mut plan) = *plan {
+        plan.sleep(interval)?;
+    }
+    if let Some(ref path) = options.checkpoint {
+        Checkpoint::of(loop_manager)?.save(path)?;
+    }
+    let started = Instant::now();
+    if options.spinner && io::stderr().is_terminal() && interval > Duration::from_secs(0) {
+        let mut spinner = Spinner::default();
+        signals::sleep_with(interval, |remaining| spinner.draw(remaining))?;
+        eprintln!();
+    } else {
+        signals::sleep(interval)?;
+    }
+    trace!(
+        "Slept for {:?} of {:?} before attempt {}",
+        started.elapsed(),
+        interval,
+        loop_manager.iteration + 1
+    );
+    Ok(())
+}
+
+/// Run `cmd` (the program followed by its arguments) until it succeeds, as
+/// configured by `options`, returning the final exit status
+pub fn retry_command(cmd: &[String], options: &RetryOptions) -> Result<ExitStatus> {
+    options.warn_unsupported();
+    let mut task = CommandTask::new(cmd, options);
+    let mut loop_manager = options.build_loop_manager();
+    Ok(retry(&mut task, options, &mut loop_manager)?.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use quicli::prelude::*;
+    use std::iter;
+
+    use super::*;
+    use attempt::exit_status;
+
+    fn options(args: &[&str]) -> RetryOptions {
+        RetryOptions::from_iter(iter::once("retry").chain(args.iter().cloned()))
+    }
+
+    /// Retry a task which exits with each of `codes` in turn, and 0 after
+    /// them, returning the result and how many attempts were made
+    fn run(args: &[&str], codes: &[i32]) -> (Result<Attempt>, usize) {
+        let options = options(args);
+        let mut loop_manager = options.build_loop_manager();
+        let mut attempts = 0;
+        let result = retry(
+            &mut |_: &LoopManager| {
+                let code = codes.get(attempts).cloned().unwrap_or(0);
+                attempts += 1;
+                Ok(Attempt::exited(exit_status(code)))
+            },
+            &options,
+            &mut loop_manager,
+        );
+        (result, attempts)
+    }
+
+    #[test]
+    fn retries_a_closure_until_it_succeeds() {
+        let (result, attempts) = run(&[], &[1, 1]);
+        assert_eq!(result.unwrap().code(), Some(0));
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn a_closure_which_errors_stops_retrying() {
+        let options = options(&[]);
+        let mut loop_manager = options.build_loop_manager();
+        let result = retry(
+            &mut |_: &LoopManager| -> Result<Attempt> { bail!("broken") },
+            &options,
+            &mut loop_manager,
+        );
+        assert!(result.is_err());
+        assert_eq!(loop_manager.iteration(), 0);
+    }
+
+    #[test]
+    fn maximum_iterations_runs_the_command_that_many_times() {
+        let (result, attempts) = run(&["-m", "3"], &[1; 10]);
+        assert_eq!(attempts, 3);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Gave up after 1 initial attempt and 2 retries"
+        );
+    }
+
+    #[test]
+    fn start_iteration_counts_towards_the_maximum() {
+        let (result, attempts) = run(&["-m", "3", "--start-iteration", "2"], &[1; 10]);
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn retries_runs_the_command_once_more_than_that() {
+        for &(retries, message) in &[
+            ("0", "Gave up after 1 initial attempt and 0 retries"),
+            ("1", "Gave up after 1 initial attempt and 1 retry"),
+            ("2", "Gave up after 1 initial attempt and 2 retries"),
+        ] {
+            let (result, attempts) = run(&["--retries", retries], &[1; 10]);
+            assert_eq!(attempts, retries.parse::<usize>().unwrap() + 1);
+            assert_eq!(result.unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn retries_stop_once_the_command_succeeds() {
+        let (result, attempts) = run(&["--retries", "5"], &[1, 1]);
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn max_consecutive_failures_tolerates_blips() {
+        let args = [
+            "--max-consecutive-failures",
+            "2",
+            "--consecutive-successes",
+            "2",
+        ];
+        let (result, attempts) = run(&args, &[1, 0, 1, 0, 1, 1]);
+        assert!(matches!(
+            result.unwrap_err().downcast::<RetryError>(),
+            Ok(RetryError::ConsecutiveFailures(2))
+        ));
+        assert_eq!(attempts, 6);
+    }
+
+    type Printing = Box<dyn FnMut(&LoopManager) -> Result<Attempt> + Send>;
+
+    /// A task which prints `stdout` and exits with `code` every time
+    fn printing(stdout: &'static str, code: i32) -> Printing {
+        Box::new(move |_: &LoopManager| {
+            Ok(Attempt {
+                outcome: Outcome::Exited(exit_status(code)),
+                stdout: Some(stdout.into()),
+                stderr: None,
+            })
+        })
+    }
+
+    fn run_all(args: &[&str], tasks: Vec<Printing>) -> Result<Attempt> {
+        let options = options(args);
+        let mut loop_manager = options.build_loop_manager();
+        let mut task = AllOf::new(tasks, &options, false);
+        retry(&mut task, &options, &mut loop_manager)
+    }
+
+    #[test]
+    fn each_of_several_tasks_is_judged_by_the_conditions() {
+        let args = ["-m", "2", "-i", "0", "--until-stdout-matches", "ready"];
+        let mixed = vec![printing("nope", 0), printing("ready", 0)];
+        assert!(run_all(&args, mixed).is_err());
+        let ready = vec![printing("ready", 0), printing("ready", 1)];
+        let attempt = run_all(&args, ready).unwrap();
+        assert_eq!(attempt.stdout, Some(b"readyready".to_vec()));
+
+        let args = ["-m", "2", "-i", "0", "--invert"];
+        let mixed = vec![printing("", 0), printing("", 1)];
+        assert!(run_all(&args, mixed).is_err());
+        let failing = vec![printing("", 1), printing("", 2)];
+        assert!(run_all(&args, failing).is_ok());
+    }
+}
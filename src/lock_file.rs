@@ -0,0 +1,30 @@
+use quicli::prelude::*;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+
+/// An exclusive lock on a file, held until this is dropped. The lock goes
+/// with the open file, so the system releases it whenever we exit, even if
+/// we are killed, and a lock file left behind never needs cleaning up.
+pub struct LockFile {
+    _file: File,
+}
+
+impl LockFile {
+    /// Lock `path`, creating it if need be, or `None` if another process
+    /// already holds the lock
+    pub fn acquire(path: &Path) -> Result<Option<LockFile>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(|e| format_err!("Could not open lock file {}: {}", path.display(), e))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(LockFile { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => {
+                bail!("Could not lock {}: {}", path.display(), e)
+            }
+        }
+    }
+}
@@ -0,0 +1,615 @@
+use quicli::prelude::*;
+use rand::rngs::StdRng;
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use attempt::{CapturedOutput, Tail};
+use backoff::{Backoff, JitterMode};
+use clock::Clock;
+use display::human_duration;
+use error::{Retries, RetryError};
+
+/// Tracks the timing and number of attempts, deciding how long to wait
+/// between them and when to give up
+#[derive(Debug, Clone)]
+pub struct LoopManager {
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) start_of_day: Instant,
+    /// Time spent retrying in an earlier run, with --resume
+    pub(crate) carried_over: Duration,
+    pub(crate) timeout: Option<f64>,
+    pub(crate) budget: Option<f64>,
+    pub(crate) deadline: Option<SystemTime>,
+    /// Time from the start at which the next attempt is due, which starts
+    /// after the initial delay
+    pub(crate) next_due: Duration,
+    pub(crate) backoff: Box<dyn Backoff>,
+    pub(crate) first_interval: Option<f64>,
+    pub(crate) hinted_interval: Option<Duration>,
+    pub(crate) max_interval: Option<f64>,
+    /// Whether the backoff has grown to the max interval, after which every
+    /// wait is the max interval
+    pub(crate) steady: bool,
+    pub(crate) jitter: f64,
+    pub(crate) jitter_mode: JitterMode,
+    /// What `rng` and any random backoff were seeded with
+    pub(crate) seed: u64,
+    pub(crate) rng: StdRng,
+    pub(crate) maximum_iterations: Option<usize>,
+    pub(crate) max_failures: Option<usize>,
+    pub(crate) max_consecutive_failures: Option<usize>,
+    pub(crate) grace: Option<f64>,
+    pub(crate) iteration: usize,
+    /// Attempts since the backoff schedule started
+    pub(crate) backoff_iteration: usize,
+    pub(crate) failures: usize,
+    /// Failures since the last success
+    pub(crate) consecutive_failures: usize,
+    /// Time and attempts from which the timeout and the limits on attempts
+    /// are measured, which --watch moves on after each success
+    pub(crate) limits_from: Duration,
+    pub(crate) limits_iteration: usize,
+    pub(crate) uncounted: usize,
+    /// Whether the limits were restarted after the attempt just made, which
+    /// leaves it out of them already
+    pub(crate) limits_restarted: bool,
+    pub(crate) last_exit_code: Option<i32>,
+    /// The status of the last response with --until-http
+    pub(crate) last_http_status: Option<u16>,
+    /// The output of the most recent attempt, with --show-last-output
+    pub(crate) last_output: Option<CapturedOutput>,
+    /// The last lines of output over all the attempts, with --tail-on-failure
+    pub(crate) tail: Option<Tail>,
+    pub(crate) report_interval: Option<f64>,
+    /// Shortest time between the starts of attempts, from --max-rate
+    pub(crate) min_gap: Option<Duration>,
+    pub(crate) last_start: Option<Instant>,
+    /// Tokens for starting attempts, from --rate and --burst
+    pub(crate) bucket: Option<TokenBucket>,
+    /// Whether the command has succeeded, but not yet for the stability
+    /// window, so that giving up means it was unstable
+    pub(crate) stabilising: bool,
+    pub(crate) last_report: Instant,
+}
+
+/// A token bucket, from --rate and --burst: each attempt takes a token, and
+/// tokens come back at the rate, up to the burst
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    /// Time from the start at which the tokens were last counted
+    refilled: Duration,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub(crate) fn new(rate: f64, burst: usize) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            refilled: Duration::from_secs(0),
+        }
+    }
+
+    /// Take a token `elapsed` into retrying, returning how long to wait for
+    /// it to come back first if the bucket is empty
+    fn take(&mut self, elapsed: Duration) -> Duration {
+        let since = elapsed.saturating_sub(self.refilled);
+        self.tokens = (self.tokens + since.as_secs_f64() * self.rate).min(self.burst);
+        self.refilled = self.refilled.max(elapsed);
+        // A token owed is paid back by the time the wait is over
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::try_from_secs_f64(-self.tokens / self.rate).unwrap_or(Duration::MAX)
+        }
+    }
+}
+
+pub(crate) fn milliseconds(time_s: f64) -> u64 {
+    // Rounded, so that sums like 1.0 + 0.1 - 1.0 don't lose a millisecond
+    let ms = (time_s * 1000.0).round();
+    if ms >= u64::MAX as f64 {
+        u64::MAX
+    } else {
+        ms as u64
+    }
+}
+
+impl LoopManager {
+    /// Take the time from `clock`, starting retrying afresh from its now
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.start_of_day = clock.now();
+        self.last_report = self.start_of_day;
+        self.last_start = None;
+        if let Some(ref mut bucket) = self.bucket {
+            *bucket = TokenBucket::new(bucket.rate, bucket.burst as usize);
+        }
+        self.clock = clock;
+    }
+
+    /// Use `backoff` to decide the waits between attempts from now on
+    pub fn set_backoff(&mut self, backoff: Box<dyn Backoff>) {
+        self.backoff = backoff;
+    }
+
+    fn clamp(&self, wait: Duration) -> Duration {
+        match self.max_interval {
+            Some(m) => wait.min(Duration::from_millis(milliseconds(m))),
+            None => wait,
+        }
+    }
+
+    /// The wait before the next attempt, when it is `elapsed` into retrying,
+    /// before any jitter
+    fn wait_at(&mut self, elapsed: Duration) -> Duration {
+        let max = self
+            .max_interval
+            .map(|m| Duration::from_millis(milliseconds(m)));
+        let delay = match (self.first_interval, max) {
+            (_, Some(max)) if self.steady => max,
+            (Some(first), _) if self.backoff_iteration == 1 => {
+                Duration::from_millis(milliseconds(first))
+            }
+            _ => self.backoff.next_delay(self.backoff_iteration, elapsed),
+        };
+        if max.is_some_and(|max| delay >= max) && self.backoff.grows() {
+            // Polling at the max interval from now on, at regular times
+            self.steady = true;
+        }
+        if !self.backoff.scheduled() {
+            // Counted from now, rather than when the attempt was due
+            self.next_due = elapsed;
+        }
+        self.next_due = self.next_due.saturating_add(delay);
+        let wait = self.next_due.checked_sub(elapsed).unwrap_or_default();
+        let clamped = self.clamp(wait);
+        if clamped < wait {
+            // The schedule doesn't run ahead of the longest wait
+            self.next_due = elapsed + clamped;
+        }
+        clamped
+    }
+
+    pub fn interval(&mut self) -> Result<Duration> {
+        let elapsed = self.elapsed()?;
+        // A hint from the command is taken as it is, without jitter, and the
+        // schedule carries on from after it
+        if let Some(hint) = self.hinted_interval.take() {
+            let wait = self.clamp(hint);
+            self.next_due = elapsed + wait;
+            return Ok(wait);
+        }
+        let wait = self.wait_at(elapsed);
+        Ok(self.apply_jitter(wait))
+    }
+
+    /// The waits before each of the first `retries` retries, assuming every
+    /// attempt fails instantly, and before any jitter
+    pub fn plan(&self, retries: usize) -> Vec<Duration> {
+        let mut planner = self.clone();
+        let mut now = self.next_due;
+        (1..=retries)
+            .map(|n| {
+                planner.backoff_iteration = self.backoff_iteration + n;
+                let wait = planner.wait_at(now);
+                now += wait;
+                wait
+            })
+            .collect()
+    }
+
+    fn apply_jitter(&mut self, wait: Duration) -> Duration {
+        self.jitter_mode.apply(wait, self.jitter, &mut self.rng)
+    }
+
+    /// Time since retrying started, by the monotonic clock (unless another
+    /// clock has been set), so that changes to the system clock don't affect
+    /// the waits or the timeout
+    pub fn elapsed(&self) -> Result<Duration> {
+        Ok(self
+            .clock
+            .now()
+            .saturating_duration_since(self.start_of_day)
+            + self.carried_over)
+    }
+
+    /// The seed the random waits are drawn with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The number of attempts so far which were followed by another
+    pub fn iteration(&self) -> usize {
+        self.iteration
+    }
+
+    /// The last lines of output over all the attempts, if they are kept
+    pub fn tail(&self) -> Option<Vec<String>> {
+        self.tail.as_ref().map(Tail::lines)
+    }
+
+    /// The exit code of the most recent attempt, if it exited normally
+    pub fn last_exit_code(&self) -> Option<i32> {
+        self.last_exit_code
+    }
+
+    /// The stdout of the most recent attempt, if it was kept
+    pub fn last_stdout(&self) -> Option<&[u8]> {
+        self.last_output.as_ref()?.0.as_deref()
+    }
+
+    /// The stderr of the most recent attempt, if it was kept
+    pub fn last_stderr(&self) -> Option<&[u8]> {
+        self.last_output.as_ref()?.1.as_deref()
+    }
+
+    fn give_up(&self, error: RetryError) -> Result<()> {
+        if self.stabilising {
+            return Err(RetryError::Unstable())?;
+        }
+        Err(error)?
+    }
+
+    /// Fail if the time budget is spent, so that no more attempts are started
+    pub fn check_budget(&self) -> Result<()> {
+        if let Some(b) = self.budget {
+            if self.elapsed()? >= Duration::from_millis(milliseconds(b)) {
+                return self.give_up(RetryError::Timeout());
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether we are still in the grace period, when attempts don't count
+    /// towards the limits
+    fn in_grace(&self) -> Result<bool> {
+        Ok(match self.grace {
+            Some(g) => self.elapsed()? < Duration::from_millis(milliseconds(g)),
+            None => false,
+        })
+    }
+
+    /// Count a failed attempt, failing if that makes too many
+    pub fn record_failure(&mut self) -> Result<()> {
+        if self.in_grace()? {
+            return Ok(());
+        }
+        self.failures += 1;
+        self.consecutive_failures += 1;
+        if let Some(m) = self.max_failures {
+            if self.failures >= m {
+                return self.give_up(RetryError::MaxFailures(self.failures));
+            }
+        }
+        if let Some(m) = self.max_consecutive_failures {
+            if self.consecutive_failures >= m {
+                return self.give_up(RetryError::ConsecutiveFailures(self.consecutive_failures));
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a successful attempt, which ends any run of failures
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+    }
+
+    pub fn step(&mut self) -> Result<()> {
+        if let Some(t) = self.timeout {
+            if self.elapsed()? - self.limits_from >= Duration::from_millis(milliseconds(t)) {
+                return self.give_up(RetryError::Timeout());
+            }
+        }
+        self.check_budget()?;
+
+        // Unlike the timeout, the deadline is a time of day, so it follows the
+        // system clock
+        if let Some(d) = self.deadline {
+            if SystemTime::now() >= d {
+                return self.give_up(RetryError::DeadlineExceeded());
+            }
+        }
+
+        // The attempt just made is number iteration + 1, so this allows
+        // exactly m attempts, not counting any in the grace period
+        let restarted = mem::replace(&mut self.limits_restarted, false);
+        if self.in_grace()? && !restarted {
+            self.uncounted += 1;
+        }
+        if let Some(m) = self.maximum_iterations {
+            if self.iteration + 1 - self.uncounted - self.limits_iteration >= m {
+                let retries = self.iteration.saturating_sub(self.limits_iteration);
+                return self.give_up(RetryError::MaximumIterations(Retries(retries)));
+            }
+        }
+
+        self.iteration += 1;
+        self.backoff_iteration += 1;
+        Ok(())
+    }
+
+    /// Measure the timeout and the limits on attempts and failures afresh,
+    /// after the attempt just made. Called before `step`.
+    pub fn restart_limits(&mut self) -> Result<()> {
+        self.limits_from = self.elapsed()?;
+        self.limits_iteration = self.iteration + 1 - self.uncounted;
+        self.limits_restarted = true;
+        self.failures = 0;
+        Ok(())
+    }
+
+    /// Carry on as if `iteration` attempts had already been made, so that the
+    /// backoff and the limits pick up where an earlier run left off
+    pub fn start_at(&mut self, iteration: usize) {
+        self.iteration = iteration;
+        self.backoff_iteration = iteration;
+    }
+
+    /// Start the backoff again, so that the next wait is the first interval
+    /// from now, without affecting the limits. Called after `step`.
+    pub fn reset_backoff(&mut self) -> Result<()> {
+        // As if the attempt just made was the first, and started now
+        self.next_due = self.elapsed()?;
+        self.backoff_iteration = 1;
+        self.steady = false;
+        self.backoff.reset();
+        Ok(())
+    }
+
+    /// A one line description of how a run with `result` went, for people
+    pub fn summary<T>(&self, result: &Result<T>) -> Result<String> {
+        let attempts = self.iteration + 1;
+        let progress = format!(
+            "{} attempt{} in {}",
+            attempts,
+            if attempts == 1 { "" } else { "s" },
+            human_duration(self.elapsed()?)
+        );
+        Ok(match *result {
+            Ok(_) => format!("Succeeded after {}", progress),
+            Err(ref e) => format!("Stopped after {}: {}", progress, e),
+        })
+    }
+
+    /// Count an attempt as starting now, returning how long to wait first to
+    /// keep within the maximum rate, and for a token with --rate
+    pub fn start_attempt(&mut self) -> Duration {
+        let now = self.clock.now();
+        let gap = match (self.min_gap, self.last_start) {
+            (Some(gap), Some(last)) => gap
+                .checked_sub(now.saturating_duration_since(last))
+                .unwrap_or_default(),
+            _ => Duration::from_secs(0),
+        };
+        let elapsed = now.saturating_duration_since(self.start_of_day);
+        let wait = match self.bucket {
+            Some(ref mut bucket) => gap.max(bucket.take(elapsed + gap)),
+            None => gap,
+        };
+        self.last_start = Some(self.clock.now() + wait);
+        wait
+    }
+
+    /// Whether the report interval has passed since the last report, in
+    /// which case it is counted as reported now
+    pub fn report_due(&mut self) -> bool {
+        let interval = match self.report_interval {
+            Some(r) => Duration::from_millis(milliseconds(r)),
+            None => return false,
+        };
+        let now = self.clock.now();
+        if now.saturating_duration_since(self.last_report) < interval {
+            return false;
+        }
+        self.last_report = now;
+        true
+    }
+
+    pub fn status(&self) -> Result<String> {
+        let mut status = format!(
+            "Elapsed time: {}; Iteration: {}",
+            human_duration(self.elapsed()?),
+            self.iteration
+        );
+        if let Some(http_status) = self.last_http_status {
+            status.push_str(&format!("; HTTP status: {}", http_status));
+        }
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quicli::prelude::*;
+    use std::iter;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::LoopManager;
+    use clock::MockClock;
+    use error::RetryError;
+    use options::RetryOptions;
+
+    /// A loop manager for the command line `args`, on a clock which only
+    /// moves when the test moves it
+    fn loop_manager(args: &[&str]) -> (LoopManager, Arc<MockClock>) {
+        let options = RetryOptions::from_iter(iter::once("retry").chain(args.iter().cloned()));
+        let mut loop_manager = options.build_loop_manager();
+        let clock = Arc::new(MockClock::new());
+        loop_manager.set_clock(clock.clone());
+        (loop_manager, clock)
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// The reason `result` gave up, if it did
+    fn gave_up<T>(result: Result<T>) -> Option<RetryError> {
+        result.err().and_then(|e| e.downcast::<RetryError>().ok())
+    }
+
+    #[test]
+    fn max_interval_caps_the_wait() {
+        let (mut loop_manager, _) =
+            loop_manager(&["-i", "1", "--backoff", "linear", "--max-interval", "0.5"]);
+        loop_manager.start_at(1000);
+        assert_eq!(loop_manager.interval().unwrap(), ms(500));
+    }
+
+    #[test]
+    fn step_counts_the_attempts() {
+        let (mut loop_manager, _) = loop_manager(&[]);
+        for _ in 0..3 {
+            loop_manager.step().unwrap();
+        }
+        assert_eq!(loop_manager.iteration(), 3);
+    }
+
+    #[test]
+    fn interval_is_zero_without_an_interval() {
+        let (mut loop_manager, _) = loop_manager(&[]);
+        loop_manager.step().unwrap();
+        assert_eq!(loop_manager.interval().unwrap(), ms(0));
+    }
+
+    #[test]
+    fn first_interval_is_only_the_first_wait() {
+        let (mut loop_manager, clock) = loop_manager(&["-i", "2", "--first-interval", "0.1"]);
+        loop_manager.step().unwrap();
+        assert_eq!(loop_manager.interval().unwrap(), ms(100));
+        clock.advance(ms(100));
+        loop_manager.step().unwrap();
+        assert_eq!(loop_manager.interval().unwrap(), ms(2000));
+    }
+
+    #[test]
+    fn interval_is_zero_once_the_next_attempt_is_overdue() {
+        let (mut loop_manager, clock) = loop_manager(&["-i", "1"]);
+        clock.advance(Duration::from_secs(60 * 60));
+        loop_manager.step().unwrap();
+        assert_eq!(loop_manager.interval().unwrap(), ms(0));
+    }
+
+    #[test]
+    fn start_iteration_carries_on_the_backoff() {
+        let (mut loop_manager, _) =
+            loop_manager(&["-i", "1", "--backoff", "linear", "--start-iteration", "2"]);
+        loop_manager.step().unwrap();
+        assert_eq!(loop_manager.interval().unwrap(), ms(3000));
+    }
+
+    /// The waits before the first `n` retries, with each attempt failing
+    /// instantly and the wait then taken in full
+    fn waits(loop_manager: &mut LoopManager, clock: &MockClock, n: usize) -> Vec<Duration> {
+        (0..n)
+            .map(|_| {
+                loop_manager.step().unwrap();
+                let wait = loop_manager.interval().unwrap();
+                clock.advance(wait);
+                wait
+            })
+            .collect()
+    }
+
+    #[test]
+    fn backoff_holds_steady_at_the_max_interval() {
+        let (mut loop_manager, clock) =
+            loop_manager(&["-i", "1", "--backoff", "exponential", "--max-interval", "3"]);
+        assert_eq!(
+            waits(&mut loop_manager, &clock, 5),
+            vec![ms(1000), ms(2000), ms(3000), ms(3000), ms(3000)]
+        );
+        assert!(loop_manager.steady);
+        // Attempts then start every max interval, whatever they take
+        clock.advance(ms(500));
+        loop_manager.step().unwrap();
+        assert_eq!(loop_manager.interval().unwrap(), ms(2500));
+    }
+
+    #[test]
+    fn timeout_gives_up_once_it_has_passed() {
+        let (mut loop_manager, clock) = loop_manager(&["-t", "10"]);
+        clock.advance(ms(9999));
+        loop_manager.step().unwrap();
+        clock.advance(ms(1));
+        assert!(matches!(
+            gave_up(loop_manager.step()),
+            Some(RetryError::Timeout())
+        ));
+    }
+
+    #[test]
+    fn a_slow_attempt_eats_into_the_interval() {
+        let (mut loop_manager, clock) = loop_manager(&["-i", "2"]);
+        clock.advance(ms(500));
+        loop_manager.step().unwrap();
+        assert_eq!(loop_manager.interval().unwrap(), ms(1500));
+    }
+
+    #[test]
+    fn deadline_gives_up_once_it_has_passed() {
+        let (mut passed, _) = loop_manager(&["--deadline", "2000-01-01T00:00:00Z"]);
+        assert!(matches!(
+            gave_up(passed.step()),
+            Some(RetryError::DeadlineExceeded())
+        ));
+        let (mut to_come, _) = loop_manager(&["--deadline", "9999-01-01T00:00:00Z"]);
+        to_come.step().unwrap();
+    }
+
+    #[test]
+    fn watch_restarts_the_limits_in_the_grace_period() {
+        let (mut loop_manager, clock) = loop_manager(&["--watch", "--grace", "5", "-m", "3"]);
+        for _ in 0..3 {
+            loop_manager.restart_limits().unwrap();
+            loop_manager.step().unwrap();
+        }
+        // Failures once the grace period is over count from the last success
+        clock.advance(Duration::from_secs(5));
+        loop_manager.step().unwrap();
+        loop_manager.step().unwrap();
+        assert!(matches!(
+            gave_up(loop_manager.step()),
+            Some(RetryError::MaximumIterations(_))
+        ));
+    }
+
+    #[test]
+    fn a_success_resets_the_consecutive_failures() {
+        let (mut loop_manager, _) = loop_manager(&["--max-consecutive-failures", "3"]);
+        loop_manager.record_failure().unwrap();
+        loop_manager.record_failure().unwrap();
+        loop_manager.record_success();
+        loop_manager.record_failure().unwrap();
+        loop_manager.record_failure().unwrap();
+        assert!(matches!(
+            gave_up(loop_manager.record_failure()),
+            Some(RetryError::ConsecutiveFailures(3))
+        ));
+    }
+
+    #[test]
+    fn rate_allows_a_burst_then_throttles() {
+        let (mut loop_manager, clock) = loop_manager(&["--rate", "2", "--burst", "3"]);
+        let mut starts = || {
+            (0..5)
+                .map(|_| {
+                    let wait = loop_manager.start_attempt();
+                    clock.advance(wait);
+                    wait
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(starts(), vec![ms(0), ms(0), ms(0), ms(500), ms(500)]);
+        // The bucket fills up again while no attempts are made
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(starts(), vec![ms(0), ms(0), ms(0), ms(500), ms(500)]);
+    }
+}
@@ -1,128 +1,642 @@
 /// Retry is a command line utility to help run commands until success
+extern crate chrono;
 #[macro_use]
 extern crate quicli;
-#[macro_use]
-extern crate failure;
+extern crate retry;
+extern crate toml;
+
+mod config;
+mod heartbeat;
+mod lock_file;
+mod notify;
+mod pid_file;
 
+use chrono::{DateTime, Local};
+use config::Config;
+use heartbeat::Heartbeat;
+use lock_file::LockFile;
+use pid_file::PidFile;
 use quicli::prelude::*;
-use std::process::Command;
-use std::thread;
-use std::time::{Duration, SystemTime};
+use retry::{
+    human_duration, paint, parse_count, AllOf, Attempt, Color, CommandTask, JitterMode,
+    LoopManager, Outcome, Report, RetryError, RetryOptions, Seconds, Task,
+};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+/// The modes, and how retry's exit status says why it stopped, for --help
+const AFTER_HELP: &str = "MODES:
+    The command may instead be one of these, to wait for something without
+    running a command:
+    tcp <host:port>  Wait until something accepts TCP connections there, as
+                     with --until-tcp
+    http <url>       Wait until a GET of the URL succeeds, as with
+                     --until-http
+    file <path>      Wait until the path exists, as with --until-file-exists
+    run <command>... Run the command as usual, even if it is named like a
+                     mode, e.g. retry run file foo.txt
+    The options which only apply to running a command, such as --and, --env
+    or --until-stdout-matches, can't be used with tcp, http or file.
+
+EXIT STATUS:
+    0      The command succeeded
+    124    Retrying timed out, passed the deadline, or an attempt timed out
+           with --timeout-aborts, or the command didn't stay successful
+           for --stability-window
+    125    The retries or failures ran out
+    130    Retrying was interrupted
+    128+n  The command was killed by signal n, with --signal-aborts
+    75     Another retry holds the --lock-file
+    127    The command wasn't found
+    126    The command couldn't be run, e.g. as it isn't executable
+    n      The command exited with abort code n
+    1      Anything else went wrong";
+
+/// Exit status when another retry holds the lock file, EX_TEMPFAIL from
+/// sysexits.h, as trying again later may work
+const LOCKED: i32 = 75;
+
+/// How often the heartbeat file is written, without --heartbeat-interval
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
 
 /// Retry runs commands in a loop until they succeed
 #[derive(Debug, StructOpt)]
+#[structopt(raw(after_help = "AFTER_HELP"))]
 struct RetryCli {
-    /// The command which you would like to run and retry
-    #[structopt(raw(required = "true", min_values = "1"))]
+    /// The command which you would like to run and retry, or a mode (see
+    /// below)
+    #[structopt(raw(
+        required_unless_one = "&[\"command_file\", \"until_file_exists\", \"until_tcp\", \"until_http\"]",
+        min_values = "1"
+    ))]
     command: Vec<String>,
+    #[structopt(
+        long = "command-file",
+        parse(from_os_str),
+        raw(conflicts_with = "\"command\"")
+    )]
+    /// Run the script in this file through $SHELL (or sh) instead of a
+    /// command; it is read once, before the first attempt
+    command_file: Option<PathBuf>,
+    #[structopt(long = "and", raw(number_of_values = "1"))]
+    /// Another shell command which has to succeed too (may be repeated); each
+    /// attempt runs those which haven't succeeded yet
+    and: Vec<String>,
+    #[structopt(long = "recheck-passed")]
+    /// With --and, run every command on each attempt, even those which have
+    /// already succeeded
+    recheck_passed: bool,
+    #[structopt(long = "parallel")]
+    /// With --and, run the commands on each attempt at the same time rather
+    /// than one after another, starting each line of their output with the
+    /// command it came from
+    parallel: bool,
+    #[structopt(
+        long = "max-parallel",
+        parse(try_from_str = "parse_count"),
+        raw(requires = "\"parallel\"")
+    )]
+    /// Run at most this many commands at the same time with --parallel
+    max_parallel: Option<usize>,
     #[structopt(flatten)]
     verbosity: Verbosity,
-    #[structopt(long = "timeout", short = "t")]
-    /// Timeout (in seconds)
-    timeout: Option<f64>,
-    #[structopt(long = "interval", short = "i")]
-    /// Interval between attempts (in seconds)
-    interval: Option<f64>,
-    #[structopt(long = "maximum-iterations", short = "m")]
-    maximum_iterations: Option<usize>,
+    #[structopt(long = "config", parse(from_os_str))]
+    /// Read options from a TOML file, with keys named after the long
+    /// options (e.g. max_interval = "5m"); options on the command line take
+    /// precedence
+    config: Option<PathBuf>,
+    #[structopt(long = "report-json", raw(min_values = "0", require_equals = "true"))]
+    /// Print a JSON summary of the run when it ends, to stdout or to the given
+    /// file (--report-json=FILE)
+    report_json: Option<Option<PathBuf>>,
+    #[structopt(long = "webhook")]
+    /// POST a JSON summary of the run to this URL if retrying fails
+    webhook: Option<String>,
+    #[structopt(long = "notify")]
+    /// Show a desktop notification saying how retrying went when it ends,
+    /// unless it was interrupted. Supported on Linux and other Unix desktops,
+    /// through notify-send, and on macOS, through osascript.
+    notify: bool,
+    #[structopt(long = "pid-file", parse(from_os_str))]
+    /// Write retry's PID to this file while it runs
+    pid_file: Option<PathBuf>,
+    #[structopt(long = "heartbeat-file", parse(from_os_str))]
+    /// Write the time to this file every --heartbeat-interval while retry
+    /// runs, including during attempts and waits, e.g. for a liveness probe
+    /// which checks how old it is. It is removed when retry exits.
+    heartbeat_file: Option<PathBuf>,
+    #[structopt(long = "heartbeat-interval", raw(requires = "\"heartbeat_file\""))]
+    /// How often to write the --heartbeat-file (e.g. 30, 500ms, 5m)
+    /// [default: 10s]
+    heartbeat_interval: Option<Seconds>,
+    #[structopt(long = "lock-file", parse(from_os_str))]
+    /// Hold an exclusive lock on this file while retrying, exiting with 75
+    /// straight away if another retry already holds it. The lock is released
+    /// whenever retry exits, even after a crash, so the file itself can be
+    /// left in place.
+    lock_file: Option<PathBuf>,
+    #[structopt(long = "once")]
+    /// Run the command just once, without retrying, and exit as it did
+    once: bool,
+    #[structopt(long = "dry-run")]
+    /// Print the schedule of attempts, and the limits on retrying, without
+    /// running the command
+    dry_run: bool,
+    #[structopt(long = "summary")]
+    /// Print a line saying how many attempts were made, and why retrying
+    /// stopped, when it ends
+    summary: bool,
+    #[structopt(long = "success-message")]
+    /// Print this to stderr if retrying succeeds, with {iterations},
+    /// {elapsed}, {elapsed_ms}, {exit_code} and {reason} filled in, e.g.
+    /// "Up after {iterations} attempts"
+    success_message: Option<String>,
+    #[structopt(long = "fail-message")]
+    /// Print this to stderr if retrying doesn't succeed, filled in likewise
+    fail_message: Option<String>,
+    #[structopt(long = "buffer-stdin")]
+    /// Read all of stdin up front, and give a copy of it to every attempt
+    buffer_stdin: bool,
+    #[structopt(flatten)]
+    options: RetryOptions,
 }
 
-/// Errors for retry
-#[derive(Debug, Fail)]
-enum RetryError {
-    #[fail(display = "Retrying command did not succeed due to timeout")]
-    Timeout(),
-    #[fail(display = "Retrying command reached maximum iterations")]
-    MaximumIterations(),
+impl RetryCli {
+    /// The same arguments with a mode in place of the command, if there is
+    /// one, turned into the options it stands for
+    fn without_mode(mut self) -> Result<RetryCli> {
+        let mode = self.command.first().cloned().unwrap_or_default();
+        let operand = |command: &[String], usage: &str| match command {
+            [_, operand] => Ok(operand.clone()),
+            _ => Err(format_err!("Expected retry [OPTIONS] {}", usage)),
+        };
+        match mode.as_str() {
+            "run" if self.command.len() > 1 => {
+                self.command.remove(0);
+            }
+            "run" => bail!("Expected retry [OPTIONS] run <command>..."),
+            "tcp" | "http" | "file" => {
+                if let Some(option) = self.command_option() {
+                    bail!(
+                        "{} needs a command, so can't be used in {} mode",
+                        option,
+                        mode
+                    );
+                }
+                let o = &mut self.options;
+                match mode.as_str() {
+                    "tcp" if o.until_tcp.is_none() => {
+                        o.until_tcp = Some(operand(&self.command, "tcp <host:port>")?);
+                    }
+                    "http" if o.until_http.is_none() => {
+                        o.until_http = Some(operand(&self.command, "http <url>")?);
+                    }
+                    "file" if o.until_file_exists.is_none() => {
+                        let path = operand(&self.command, "file <path>")?;
+                        o.until_file_exists = Some(PathBuf::from(path));
+                    }
+                    "file" => bail!("--until-file-exists can't be used in file mode as well"),
+                    _ => bail!("--until-{} can't be used in {} mode as well", mode, mode),
+                }
+                self.command.clear();
+            }
+            _ => (),
+        }
+        if self.options.until_http.is_none() {
+            if !self.options.expect_status.is_empty() {
+                bail!("--expect-status only applies to http mode or --until-http");
+            }
+            if self.options.expect_body.is_some() {
+                bail!("--expect-body only applies to http mode or --until-http");
+            }
+        }
+        Ok(self)
+    }
+
+    /// The first of the options given which only apply when a command is run,
+    /// and so don't fit the modes which run none
+    fn command_option(&self) -> Option<&'static str> {
+        let o = &self.options;
+        let given = [
+            (!self.and.is_empty(), "--and"),
+            (self.recheck_passed, "--recheck-passed"),
+            (self.parallel, "--parallel"),
+            (self.buffer_stdin, "--buffer-stdin"),
+            (o.shell, "--shell"),
+            (o.cwd.is_some(), "--cwd"),
+            (!o.env.is_empty(), "--env"),
+            (o.env_file.is_some(), "--env-file"),
+            (o.env_clear, "--env-clear"),
+            (!o.env_keep.is_empty(), "--env-keep"),
+            (o.echo, "--echo"),
+            (o.dedup_output, "--dedup-output"),
+            (o.diff_output, "--diff-output"),
+            (o.tail_on_failure.is_some(), "--tail-on-failure"),
+            (o.tee.is_some(), "--tee"),
+            (o.timestamps, "--timestamps"),
+            (o.idle_timeout.is_some(), "--idle-timeout"),
+            (o.kill_grace.is_some(), "--kill-grace"),
+            (o.nice.is_some(), "--nice"),
+            (o.max_memory.is_some(), "--max-memory"),
+            (o.retry_spawn_errors, "--retry-spawn-errors"),
+            (o.backoff_from_stdout.is_some(), "--backoff-from-stdout"),
+            (!o.interval_by_code.is_empty(), "--interval-by-code"),
+            (!o.success_codes.is_empty(), "--success-code"),
+            (o.stable_for.is_some(), "--stable-for"),
+            (o.until_stdout_matches.is_some(), "--until-stdout-matches"),
+            (o.until_stderr_matches.is_some(), "--until-stderr-matches"),
+            (
+                o.until_stdout_not_matches.is_some(),
+                "--until-stdout-not-matches",
+            ),
+            (o.until_line.is_some(), "--until-line"),
+            (o.until_stderr_empty, "--until-stderr-empty"),
+            (o.until_stderr_nonempty, "--until-stderr-nonempty"),
+            (o.keep_stderr_whitespace, "--keep-stderr-whitespace"),
+            (o.require_both, "--require-both"),
+            (!o.abort_codes.is_empty(), "--abort-code"),
+            (!o.exit_code_map.is_empty(), "--exit-code-map"),
+            (o.signal_is_failure, "--signal-is-failure"),
+            (o.signal_aborts, "--signal-aborts"),
+        ];
+        given
+            .iter()
+            .find(|&&(given, _)| given)
+            .map(|&(_, option)| option)
+    }
+
+    /// Fill in any options not given on the command line from `path`
+    fn with_config(path: &Path) -> Result<RetryCli> {
+        let config = Config::load(path)?;
+        let matches = RetryCli::clap().get_matches();
+        let mut args = env::args();
+        let merged = args
+            .next()
+            .into_iter()
+            // Put the config options first, so they come before the command
+            .chain(config.args(&matches))
+            .chain(args);
+        Ok(RetryCli::from_iter(merged))
+    }
 }
 
-#[derive(Debug)]
-struct LoopManager {
-    start_of_day: SystemTime,
-    timeout: Option<f64>,
-    interval: Option<f64>,
-    maximum_iterations: Option<usize>,
-    iteration: usize,
+/// How many retries a dry run shows when the number of attempts is unlimited
+const DRY_RUN_PREVIEW: usize = 10;
+
+/// A wait as shown by --dry-run, where a wait which has grown as long as a
+/// wait can be is unbounded
+fn plan_wait(wait: Duration) -> String {
+    if wait >= Duration::from_millis(u64::MAX) {
+        String::from("unbounded")
+    } else {
+        human_duration(wait)
+    }
 }
 
-impl RetryCli {
-    fn build_loop_manager(&self) -> LoopManager {
-        LoopManager {
-            start_of_day: SystemTime::now(),
-            timeout: self.timeout,
-            interval: self.interval,
-            maximum_iterations: self.maximum_iterations,
-            iteration: 0,
+/// Describe what retrying `args` would do, for --dry-run
+fn print_plan(args: &RetryCli, loop_manager: &LoopManager) {
+    let options = &args.options;
+    let limit = |limit: Option<String>| limit.unwrap_or_else(|| String::from("none"));
+    println!("Command: {}", args.command.join(" "));
+    println!(
+        "Timeout: {}",
+        limit(options.timeout.map(|t| human_duration(t.duration())))
+    );
+    println!(
+        "Budget: {}",
+        limit(options.budget.map(|b| human_duration(b.duration())))
+    );
+    println!(
+        "Deadline: {}",
+        limit(
+            options
+                .deadline
+                .map(|d| DateTime::<Local>::from(d).to_rfc3339())
+        )
+    );
+    println!(
+        "Retries: {}",
+        limit(options.max_attempts().map(|m| (m - 1).to_string()))
+    );
+    match options.jitter_mode {
+        JitterMode::Proportional if options.jitter > 0.0 => {
+            println!("Jitter: up to {}% either way", options.jitter * 100.0)
         }
+        JitterMode::Proportional => (),
+        JitterMode::Full => println!("Jitter: full, up to the whole wait"),
+        JitterMode::Equal => println!("Jitter: equal, up to half the wait"),
     }
-}
+    println!();
 
-fn milliseconds(time_s: f64) -> u64 {
-    (time_s * 1000.0) as u64
+    let first = loop_manager.iteration() + 1;
+    let retries = match options.max_attempts() {
+        Some(m) => m.saturating_sub(first),
+        None => DRY_RUN_PREVIEW,
+    };
+    println!("Attempt  Wait before");
+    println!(
+        "{:>7}  {}",
+        first,
+        plan_wait(options.delay.map(|d| d.duration()).unwrap_or_default())
+    );
+    for (n, &wait) in loop_manager.plan(retries).iter().enumerate() {
+        println!("{:>7}  {}", first + n + 1, plan_wait(wait));
+    }
+    if options.max_attempts().is_none() {
+        println!("{:>7}", "...");
+    }
 }
 
-impl LoopManager {
-    fn interval(&self) -> Result<Duration> {
-        if let Some(i) = self.interval {
-            Ok(
-                Duration::from_millis(milliseconds(i * (self.iteration as f64)))
-                    - self.start_of_day.elapsed()?,
-            )
+/// Pass on the output of the successful attempt, for
+/// --no-inherit-stdout-on-retry, sending its stdout to stderr if our stdout
+/// is reserved
+fn replay_output(attempt: &Attempt, stdout_reserved: bool) -> Result<()> {
+    if let Some(ref output) = attempt.stdout {
+        if stdout_reserved {
+            io::stderr().write_all(output)?;
         } else {
-            Ok(Duration::from_secs(0))
+            io::stdout().write_all(output)?;
         }
     }
-
-    fn elapsed(&self) -> Result<Duration> {
-        Ok(self.start_of_day.elapsed()?)
+    if let Some(ref output) = attempt.stderr {
+        io::stderr().write_all(output)?;
     }
+    Ok(())
+}
 
-    fn step(&mut self) -> Result<()> {
-        if let Some(t) = self.timeout {
-            if !(self.elapsed()? < Duration::from_millis(milliseconds(t))) {
-                return Err(RetryError::Timeout())?;
+/// Print what the last attempt output, for --show-last-output
+fn show_last_output(loop_manager: &LoopManager) -> Result<()> {
+    let mut stderr = io::stderr();
+    for (name, output) in &[
+        ("stdout", loop_manager.last_stdout()),
+        ("stderr", loop_manager.last_stderr()),
+    ] {
+        if let Some(output) = output.filter(|output| !output.is_empty()) {
+            writeln!(stderr, "--- {} of the last attempt ---", name)?;
+            stderr.write_all(output)?;
+            if !output.ends_with(b"\n") {
+                writeln!(stderr)?;
             }
         }
+    }
+    Ok(())
+}
+
+main!(|args: RetryCli, log_level: verbosity| {
+    let args = match args.config {
+        Some(ref path) => RetryCli::with_config(path)?,
+        None => args,
+    }
+    .without_mode()?;
+    debug!("Got arguments: {:?}", args);
+    args.options.warn_unsupported();
+    retry::handle_signals()?;
+    if args.options.die_with_parent {
+        retry::die_with_parent()?;
+    }
+
+    if args.dry_run {
+        print_plan(&args, &args.options.build_loop_manager());
+        return Ok(());
+    }
 
-        if let Some(m) = self.maximum_iterations {
-            if !(self.iteration + 1 < m) {
-                return Err(RetryError::MaximumIterations())?;
+    // Held until we exit
+    let _lock = match args.lock_file {
+        Some(ref path) => match LockFile::acquire(path)? {
+            Some(lock) => Some(lock),
+            None => {
+                eprintln!("error: {} is locked by another retry", path.display());
+                process::exit(LOCKED);
             }
+        },
+        None => None,
+    };
+
+    let task = match args.command_file {
+        Some(ref path) => {
+            let script = fs::read_to_string(path)
+                .map_err(|e| format_err!("Could not read {}: {}", path.display(), e))?;
+            CommandTask::script(&script, &args.options)
+        }
+        None => CommandTask::new(&args.command, &args.options),
+    };
+    let mut tasks: Vec<CommandTask> = iter::once(task)
+        .chain(
+            args.and
+                .iter()
+                .map(|script| CommandTask::script(script, &args.options)),
+        )
+        .collect();
+    if args.report_json == Some(None) {
+        tasks.iter_mut().for_each(CommandTask::reserve_stdout);
+    }
+    if args.buffer_stdin {
+        let mut input = Vec::new();
+        io::stdin().read_to_end(&mut input)?;
+        for task in &mut tasks {
+            task.feed_stdin(input.clone());
+        }
+    }
+    if args.parallel {
+        let main = match args.command_file {
+            Some(ref path) => path.display().to_string(),
+            None => args.command.join(" "),
+        };
+        for (task, label) in tasks.iter_mut().zip(iter::once(&main).chain(&args.and)) {
+            task.label_output(label);
         }
+    }
+    let parallel = args.max_parallel.unwrap_or(tasks.len());
+    let mut task = AllOf::new(tasks, &args.options, args.recheck_passed);
+    if args.parallel {
+        task.in_parallel(parallel);
+    }
+    let pid_file = match args.pid_file {
+        Some(ref path) => Some(PidFile::create(path)?),
+        None => None,
+    };
+    let heartbeat = match args.heartbeat_file {
+        Some(ref path) => Some(Heartbeat::start(
+            path,
+            args.heartbeat_interval
+                .map_or(DEFAULT_HEARTBEAT_INTERVAL, Seconds::duration),
+        )?),
+        None => None,
+    };
+    let mut loop_manager = args.options.build_loop_manager();
+    debug!("Loop manager initialized: {:?}", loop_manager);
 
-        self.iteration += 1;
-        Ok(())
+    if args.once {
+        let attempt = match task.attempt(&loop_manager) {
+            Err(e) => match e.downcast::<RetryError>() {
+                Ok(error) => {
+                    eprintln!("error: {}", error);
+                    drop(pid_file);
+                    drop(heartbeat);
+                    process::exit(error.exit_code());
+                }
+                Err(e) => return Err(e),
+            },
+            Ok(attempt) => attempt,
+        };
+        // Exiting skips destructors, so the PID and heartbeat files have to
+        // go first
+        drop(pid_file);
+        drop(heartbeat);
+        // Exit as the command did, or as retry would have stopped
+        process::exit(match attempt.outcome {
+            Outcome::TimedOut(_) => RetryError::AttemptTimedOut(1).exit_code(),
+            Outcome::Interrupted(_) => RetryError::Interrupted().exit_code(),
+            Outcome::Matched(_) => 0,
+            Outcome::Exited(status) => match (status.code(), attempt.signal()) {
+                (Some(rc), _) => rc,
+                (None, Some(signal)) => RetryError::Signalled(signal).exit_code(),
+                (None, None) => 1,
+            },
+        });
     }
 
-    fn status(&self) -> Result<String> {
-        Ok(format!(
-            "Elapsed time: {:?}; Iteration: {}",
-            self.elapsed()?,
-            self.iteration
-        ))
+    let result = retry::retry(&mut task, &args.options, &mut loop_manager);
+
+    if let Ok(ref attempt) = result {
+        if args.options.no_inherit_stdout_on_retry && !args.options.quiet {
+            replay_output(attempt, args.report_json == Some(None))?;
+        }
     }
-}
 
-main!(|args: RetryCli, log_level: verbosity| {
-    debug!("Got arguments: {:?}", args);
+    let interrupted = result.as_ref().err().is_some_and(|e| {
+        matches!(
+            e.downcast_ref::<RetryError>(),
+            Some(RetryError::Interrupted())
+        )
+    });
+    if args.options.progress && loop_manager.iteration() > 0 && !interrupted {
+        // Finish the progress line, so it isn't overwritten by what follows
+        eprintln!();
+    }
 
-    let (cmd, cmd_args) = args.command.split_at(1);
+    if args.summary {
+        let color = if result.is_ok() {
+            Color::Green
+        } else {
+            Color::Red
+        };
+        eprintln!(
+            "{}",
+            paint(
+                &loop_manager.summary(&result)?,
+                color,
+                retry::color_enabled(args.options.no_color)
+            )
+        );
+    }
 
-    let mut loop_manager = args.build_loop_manager();
-    debug!("Loop manager initialized: {:?}", loop_manager);
+    let message = if result.is_ok() {
+        &args.success_message
+    } else {
+        &args.fail_message
+    };
+    if let Some(ref template) = *message {
+        eprintln!(
+            "{}",
+            Report::new(&args.command, &loop_manager, &result)?.render(template)
+        );
+    }
 
-    loop {
-        let status = Command::new(&cmd[0]).args(cmd_args).status()?;
-        if let Some(rc) = status.code() {
-            if rc == 0 {
-                break;
+    if let Some(ref destination) = args.report_json {
+        Report::new(&args.command, &loop_manager, &result)?
+            .write(destination.as_ref().map(|p| p.as_path()))?;
+    }
+
+    if let Some(ref url) = args.webhook {
+        let gave_up = result
+            .as_ref()
+            .err()
+            .is_some_and(|e| e.downcast_ref::<RetryError>().is_some());
+        if gave_up {
+            // The webhook is only a notification, so it mustn't change how
+            // we exit
+            if let Err(e) = Report::new(&args.command, &loop_manager, &result)?.post(url) {
+                eprintln!("warning: webhook {} failed: {}", url, e);
             }
         }
+    }
 
-        loop_manager.step()?;
+    if args.notify && !interrupted {
+        let title = if result.is_ok() {
+            "retry succeeded"
+        } else {
+            "retry failed"
+        };
+        // Likewise, a missing notification daemon mustn't stop us
+        if let Err(e) = notify::notify(title, &loop_manager.summary(&result)?) {
+            eprintln!("warning: could not show a desktop notification: {}", e);
+        }
+    }
 
-        debug!("Loop manager status: {:?}", loop_manager.status()?);
+    if args.options.json_logs {
+        Report::new(&args.command, &loop_manager, &result)?.log()?;
+    }
+    if args.options.syslog {
+        Report::new(&args.command, &loop_manager, &result)?.syslog(&args.options)?;
+    }
 
-        thread::sleep(loop_manager.interval()?);
+    if let Err(e) = result {
+        if let Some(error) = e.downcast_ref::<RetryError>() {
+            if args.options.json_logs {
+                // Already told in the summary
+            } else if interrupted {
+                // Start a fresh line, in case the terminal echoed ^C
+                eprintln!();
+                eprintln!("{}", loop_manager.status()?);
+            } else {
+                if args.options.show_last_output {
+                    show_last_output(&loop_manager)?;
+                }
+                if let Some(lines) = loop_manager.tail() {
+                    eprintln!("--- last {} lines of output ---", lines.len());
+                    for line in lines {
+                        eprintln!("{}", line);
+                    }
+                }
+                eprintln!("error: {}", error);
+            }
+            drop(pid_file);
+            drop(heartbeat);
+            process::exit(error.exit_code());
+        }
+        return Err(e);
     }
 });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn without_mode(args: &[&str]) -> Result<RetryCli> {
+        RetryCli::from_iter(iter::once("retry").chain(args.iter().cloned())).without_mode()
+    }
+
+    #[test]
+    fn a_mode_takes_the_place_of_its_option() {
+        let args = without_mode(&["tcp", "localhost:80"]).unwrap();
+        assert_eq!(args.options.until_tcp, Some("localhost:80".to_string()));
+        assert!(args.command.is_empty());
+        assert!(without_mode(&["--until-tcp", "a:1", "tcp", "b:2"]).is_err());
+        assert!(without_mode(&["--until-file-exists", "a", "file", "b"]).is_err());
+    }
+
+    #[test]
+    fn a_mode_rejects_the_options_of_a_command() {
+        assert!(without_mode(&["--env", "A=1", "file", "a"]).is_err());
+        assert!(without_mode(&["--until-stdout-matches", "up", "tcp", "a:1"]).is_err());
+        assert!(without_mode(&["--attempt-timeout", "1", "tcp", "a:1"]).is_ok());
+        assert!(without_mode(&["--expect-status", "204", "http", "http://a/"]).is_ok());
+        assert!(without_mode(&["--expect-status", "204", "--", "true"]).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use quicli::prelude::*;
+use std::io;
+use std::process::{Command, Stdio};
+
+/// Show a desktop notification headed `title`, saying `body`
+pub fn notify(title: &str, body: &str) -> Result<()> {
+    let mut command = notifier(title, body)?;
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => format_err!(
+                "{} isn't installed",
+                command.get_program().to_string_lossy()
+            ),
+            _ => e.into(),
+        })?;
+    if !status.success() {
+        bail!(
+            "{} exited with {}",
+            command.get_program().to_string_lossy(),
+            status
+        );
+    }
+    Ok(())
+}
+
+/// AppleScript has no way to quote a string other than with backslashes
+#[cfg(target_os = "macos")]
+fn applescript_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(target_os = "macos")]
+fn notifier(title: &str, body: &str) -> Result<Command> {
+    let mut command = Command::new("osascript");
+    command.arg("-e").arg(format!(
+        "display notification {} with title {}",
+        applescript_string(body),
+        applescript_string(title)
+    ));
+    Ok(command)
+}
+
+/// Through the desktop's notification daemon, which notify-send talks to
+#[cfg(all(unix, not(target_os = "macos")))]
+fn notifier(title: &str, body: &str) -> Result<Command> {
+    let mut command = Command::new("notify-send");
+    command.arg("--app-name=retry").arg(title).arg(body);
+    Ok(command)
+}
+
+#[cfg(not(unix))]
+fn notifier(_title: &str, _body: &str) -> Result<Command> {
+    bail!("Desktop notifications are only supported on Linux and macOS")
+}
@@ -0,0 +1,1071 @@
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveTime, TimeZone};
+use quicli::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::iter;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use attempt::Tail;
+use backoff::{Backoff, BackoffStrategy, Exponential, JitterMode, Schedule};
+use clock::{Clock, MonotonicClock};
+use loop_manager::{milliseconds, LoopManager, TokenBucket};
+use syslog::SyslogFacility;
+
+/// Options controlling how a command is run and retried
+#[derive(Debug, StructOpt)]
+pub struct RetryOptions {
+    #[structopt(long = "shell", short = "s")]
+    /// Run the command through $SHELL (or sh) with -c, joining its arguments
+    /// with spaces
+    pub shell: bool,
+    #[structopt(long = "cwd", parse(try_from_str = "parse_directory"))]
+    /// Directory to run the command in
+    pub cwd: Option<PathBuf>,
+    #[structopt(
+        long = "env",
+        short = "e",
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_env_var")
+    )]
+    /// Set an environment variable for the command, as KEY=VALUE (may be
+    /// repeated)
+    pub env: Vec<(String, String)>,
+    #[structopt(long = "env-file")]
+    /// Set the environment variables in this file for the command, one
+    /// KEY=VALUE a line, as in a .env file. Values may be quoted, and
+    /// --env takes precedence.
+    pub env_file: Option<EnvFile>,
+    #[structopt(long = "env-clear")]
+    /// Don't pass our own environment on to the command
+    pub env_clear: bool,
+    #[structopt(long = "env-keep", raw(number_of_values = "1"))]
+    /// Pass only this variable of our own environment on to the command,
+    /// along with any set with --env (may be repeated). Keep PATH too, if
+    /// the command needs it to find other programs.
+    pub env_keep: Vec<String>,
+    #[structopt(long = "no-inject-env")]
+    /// Don't tell the command about the retrying. Otherwise RETRY_ITERATION
+    /// holds the number of the attempt, counting from 1, and RETRY_ELAPSED_MS
+    /// the milliseconds since retrying started.
+    pub no_inject_env: bool,
+    #[structopt(long = "echo")]
+    /// Print each command line to stderr before running it, like set -x,
+    /// including the environment variables set for it
+    pub echo: bool,
+    #[structopt(long = "dedup-output")]
+    /// Only show an attempt's output when it differs from the last attempt's,
+    /// noting "(same as last)" otherwise. Output is shown once each attempt
+    /// has finished.
+    pub dedup_output: bool,
+    #[structopt(long = "diff-output")]
+    /// Like --dedup-output, but show only the lines which changed since the
+    /// last attempt's output, marked with - and +
+    pub diff_output: bool,
+    #[structopt(long = "no-inherit-stdout-on-retry")]
+    /// Hide the output of failed attempts, showing only the output of the
+    /// one which succeeds once it has finished
+    pub no_inherit_stdout_on_retry: bool,
+    #[structopt(long = "show-last-output")]
+    /// Print the output of the last attempt to stderr if retrying gives up,
+    /// even with --quiet
+    pub show_last_output: bool,
+    #[structopt(long = "tail-on-failure", parse(try_from_str = "parse_count"))]
+    /// Print the last this many lines of output, from however many attempts,
+    /// to stderr if retrying gives up, even with --quiet
+    pub tail_on_failure: Option<usize>,
+    #[structopt(long = "quiet", short = "q")]
+    /// Discard the output of the command
+    pub quiet: bool,
+    #[structopt(
+        long = "quiet-success",
+        raw(conflicts_with = "\"no_inherit_stdout_on_retry\"")
+    )]
+    /// Hide the output of attempts which exit with a success code, showing
+    /// that of the rest once each has finished. With --quiet-failure too,
+    /// nothing is shown, and --quiet hides everything whatever these say.
+    pub quiet_success: bool,
+    #[structopt(
+        long = "quiet-failure",
+        raw(conflicts_with = "\"no_inherit_stdout_on_retry\"")
+    )]
+    /// Hide the output of attempts which don't exit with a success code,
+    /// showing that of the rest once each has finished
+    pub quiet_failure: bool,
+    #[structopt(long = "tee", parse(from_os_str))]
+    /// Append the command's output to this file as well, under a heading for
+    /// each attempt
+    pub tee: Option<PathBuf>,
+    #[structopt(
+        long = "max-capture",
+        default_value = "1M",
+        parse(try_from_str = "parse_size")
+    )]
+    /// Most of each stream kept from an attempt whose output is captured, in
+    /// bytes or with a K, M or G suffix, beyond which it is cut short with a
+    /// marker. Output patterns are only matched against what is kept; --tee
+    /// and the output shown still get everything.
+    pub max_capture: u64,
+    #[structopt(long = "timestamps", raw(alias = "\"timestamp-output\""))]
+    /// Start each line of the command's output with the time it was read,
+    /// including in the --tee file
+    pub timestamps: bool,
+    #[structopt(long = "progress")]
+    /// Show the elapsed time and iteration on stderr after each failed
+    /// attempt, even with --quiet
+    pub progress: bool,
+    #[structopt(long = "log-file", parse(from_os_str))]
+    /// Append a tab separated line for each attempt to this file, holding
+    /// the time, attempt number, duration in milliseconds and exit code
+    pub log_file: Option<PathBuf>,
+    #[structopt(long = "plan-file", parse(from_os_str))]
+    /// Append a line of JSON for each attempt to this file, holding when it
+    /// started and how long it took, in milliseconds, its exit code and the
+    /// wait after it, as the schedule actually followed
+    pub plan_file: Option<PathBuf>,
+    #[structopt(long = "spinner")]
+    /// Count down to the next attempt on stderr, if it is a terminal
+    pub spinner: bool,
+    #[structopt(long = "report-interval")]
+    /// Print how long retrying has been going to stderr at most this often,
+    /// between attempts (e.g. 30, 500ms, 5m)
+    pub report_interval: Option<Seconds>,
+    #[structopt(
+        long = "json-logs",
+        raw(
+            conflicts_with_all = "&[\"progress\", \"spinner\", \"report_interval\", \"summary\", \"show_last_output\", \"tail_on_failure\"]"
+        )
+    )]
+    /// Write a line of JSON to stderr for each attempt, and one summing up
+    /// the run at the end, in place of the usual messages. The command's own
+    /// output is passed through as it is.
+    pub json_logs: bool,
+    #[structopt(long = "syslog")]
+    /// Send a JSON summary of the run to syslog at the end, and with
+    /// --json-logs each attempt's line as well
+    pub syslog: bool,
+    #[structopt(long = "syslog-facility", default_value = "user")]
+    /// Facility to log to syslog under: user, daemon or local0 to local7
+    pub syslog_facility: SyslogFacility,
+    #[structopt(long = "syslog-tag", default_value = "retry")]
+    /// Name to log to syslog under
+    pub syslog_tag: String,
+    #[structopt(long = "no-color")]
+    /// Don't colour --progress, --summary and --diff-output output, as when
+    /// NO_COLOR is set or the output isn't going to a terminal
+    pub no_color: bool,
+    #[structopt(long = "timeout", short = "t", raw(env = "\"RETRY_TIMEOUT\""))]
+    /// Timeout (e.g. 30, 500ms, 5m)
+    pub timeout: Option<Seconds>,
+    #[structopt(long = "budget")]
+    /// Total time to spend retrying (e.g. 30, 500ms, 5m), which unlike
+    /// --timeout is also checked before each attempt, so none is started once
+    /// it is spent. An attempt already running is allowed to finish, so use
+    /// --attempt-timeout to bound how far over budget a slow attempt can go.
+    pub budget: Option<Seconds>,
+    #[structopt(long = "deadline", parse(try_from_str = "parse_deadline"))]
+    /// Give up at this time, either RFC 3339 (2024-01-31T14:30:00Z) or a
+    /// local clock time (14:30), which means tomorrow if it has already passed
+    pub deadline: Option<SystemTime>,
+    #[structopt(long = "attempt-timeout")]
+    /// Kill a single attempt which runs longer than this (e.g. 30, 500ms, 5m)
+    pub attempt_timeout: Option<Seconds>,
+    #[structopt(long = "idle-timeout")]
+    /// Kill a single attempt which goes this long without any output (e.g.
+    /// 30, 500ms, 5m), however long it has been running. This counts as the
+    /// attempt timing out, as with --attempt-timeout.
+    pub idle_timeout: Option<Seconds>,
+    #[structopt(long = "kill-grace")]
+    /// When an attempt exceeds --attempt-timeout, send it SIGTERM and give it
+    /// this long to exit before killing it (e.g. 30, 500ms, 5m). Attempts are
+    /// killed straight away on Windows.
+    pub kill_grace: Option<Seconds>,
+    #[structopt(long = "warn-slow")]
+    /// Log a warning, shown with -v, when an attempt takes longer than this
+    /// (e.g. 30, 500ms, 5m), say to notice a command which hangs for a while
+    /// before --attempt-timeout would kill it
+    pub warn_slow: Option<Seconds>,
+    #[structopt(long = "nice", raw(allow_hyphen_values = "true"))]
+    /// Run the command at this niceness relative to ours, as with nice(1),
+    /// so 10 runs it at a lower priority. On Windows, any positive value runs
+    /// it below normal priority, or idle from 15 up.
+    pub nice: Option<i32>,
+    #[structopt(long = "die-with-parent")]
+    /// Stop retrying, killing any attempt in flight, if whatever started
+    /// retry exits. The command is also killed if retry itself is killed.
+    /// Only supported on Linux.
+    pub die_with_parent: bool,
+    #[structopt(long = "max-memory", parse(try_from_str = "parse_size"))]
+    /// Limit the command to this much memory (address space), in bytes or
+    /// with a K, M or G suffix, so that an attempt which tries to use more
+    /// fails, and is retried, rather than exhausting the machine. Only
+    /// supported on Linux.
+    pub max_memory: Option<u64>,
+    #[structopt(long = "retry-spawn-errors")]
+    /// Count failing to start the command, e.g. as it isn't installed yet,
+    /// as a failed attempt exiting with 127 if it wasn't found or 126
+    /// otherwise, rather than stopping straight away
+    pub retry_spawn_errors: bool,
+    #[structopt(
+        long = "timeout-is-failure",
+        raw(overrides_with = "\"timeout_aborts\"")
+    )]
+    /// Retry when an attempt exceeds --attempt-timeout (the default)
+    pub timeout_is_failure: bool,
+    #[structopt(
+        long = "timeout-aborts",
+        raw(overrides_with = "\"timeout_is_failure\"")
+    )]
+    /// Stop retrying when an attempt exceeds --attempt-timeout
+    pub timeout_aborts: bool,
+    #[structopt(long = "delay")]
+    /// Wait before the first attempt (e.g. 30, 500ms, 5m)
+    pub delay: Option<Seconds>,
+    #[structopt(long = "interval", short = "i", raw(env = "\"RETRY_INTERVAL\""))]
+    /// Interval between attempts (e.g. 30, 500ms, 5m)
+    pub interval: Option<Seconds>,
+    #[structopt(long = "first-interval")]
+    /// Wait this long before the first retry only, after which the interval
+    /// backs off as usual (e.g. 30, 500ms, 5m)
+    pub first_interval: Option<Seconds>,
+    #[structopt(long = "initial-interval", raw(conflicts_with = "\"interval\""))]
+    /// Interval before the first retry, which --multiplier then grows (e.g.
+    /// 30, 500ms, 5m)
+    pub initial_interval: Option<Seconds>,
+    #[structopt(
+        long = "multiplier",
+        raw(conflicts_with_all = "&[\"backoff\", \"backoff_base\"]")
+    )]
+    /// Multiply the interval by this after each retry, so 1.5 waits 1, 1.5,
+    /// 2.25... intervals; 1.0 keeps the interval constant
+    pub multiplier: Option<f64>,
+    #[structopt(
+        long = "schedule",
+        raw(
+            use_delimiter = "true",
+            require_delimiter = "true",
+            conflicts_with_all = "&[\"backoff\", \"multiplier\", \"first_interval\"]"
+        )
+    )]
+    /// Comma separated waits before each retry in turn (e.g. 1,2,5,10,30),
+    /// after which the last one is repeated, in place of --interval and
+    /// --backoff
+    pub schedule: Vec<Seconds>,
+    #[structopt(long = "backoff-from-stdout", parse(try_from_str = "Regex::new"))]
+    /// Regex whose first capture group, when it matches the command's stdout,
+    /// gives the wait before the next attempt (e.g. 30, 500ms, 5m) in place
+    /// of the usual backoff
+    pub backoff_from_stdout: Option<Regex>,
+    #[structopt(
+        long = "interval-by-code",
+        parse(try_from_str = "parse_code_interval"),
+        raw(use_delimiter = "true", require_delimiter = "true")
+    )]
+    /// Waits to take after particular exit codes in place of the usual
+    /// backoff, as comma separated CODE=WAIT (e.g. 2=5,3=30s); a wait from
+    /// --backoff-from-stdout comes first
+    pub interval_by_code: Vec<(i32, Seconds)>,
+    #[structopt(long = "max-interval")]
+    /// Longest time to wait between attempts (e.g. 30, 500ms, 5m). Once the
+    /// backoff has grown this far, attempts start at regular intervals of it.
+    pub max_interval: Option<Seconds>,
+    #[structopt(
+        long = "jitter",
+        default_value = "0",
+        parse(try_from_str = "parse_fraction")
+    )]
+    /// Randomly vary each wait by up to this fraction (0.0 to 1.0)
+    pub jitter: f64,
+    #[structopt(long = "seed")]
+    /// Seed the random numbers which jitter and --backoff decorrelated-jitter
+    /// draw on, so that the same seed and options give the same waits every
+    /// time. Without a seed, one is picked at random, and recorded with
+    /// --report-json and --plan-file so that a run can be repeated.
+    pub seed: Option<u64>,
+    #[structopt(
+        long = "jitter-mode",
+        default_value = "proportional",
+        raw(possible_values = "&[\"proportional\", \"full\", \"equal\"]")
+    )]
+    /// How each wait is randomised: proportional varies it by up to --jitter
+    /// either way, full waits a random time up to the wait, and equal waits
+    /// half of it plus a random time up to the other half
+    pub jitter_mode: JitterMode,
+    #[structopt(long = "retries", raw(conflicts_with = "\"maximum_iterations\""))]
+    /// Give up after retrying this many times, i.e. running the command once
+    /// more than this
+    pub retries: Option<usize>,
+    #[structopt(
+        long = "maximum-iterations",
+        short = "m",
+        parse(try_from_str = "parse_count"),
+        raw(env = "\"RETRY_MAX_ITERATIONS\"")
+    )]
+    /// Give up after running the command this many times (deprecated; use
+    /// --retries, which is one less)
+    pub maximum_iterations: Option<usize>,
+    #[structopt(
+        long = "total-iterations-include-first",
+        raw(
+            possible_values = "&[\"true\", \"false\"]",
+            requires = "\"maximum_iterations\""
+        )
+    )]
+    /// Whether --maximum-iterations counts the first run of the command, or
+    /// only the retries after it, so that with false, -m 2 runs the command
+    /// up to 3 times [default: true]
+    pub total_iterations_include_first: Option<bool>,
+    #[structopt(long = "start-iteration", default_value = "0")]
+    /// Carry on from an earlier run as if this many attempts had already been
+    /// made, which count towards --retries and decide the next
+    /// wait
+    pub start_iteration: usize,
+    #[structopt(long = "checkpoint", parse(from_os_str))]
+    /// Save how far retrying has got to this file after each attempt, for
+    /// --resume to carry on from. It is removed once retrying is over, unless
+    /// it was interrupted.
+    pub checkpoint: Option<PathBuf>,
+    #[structopt(long = "resume", raw(requires = "\"checkpoint\""))]
+    /// Carry on from the --checkpoint file, if there is one, counting the
+    /// attempts, failures and time spent retrying it records towards the
+    /// limits, and continuing the backoff from where it got to
+    pub resume: bool,
+    #[structopt(long = "count-downtime", raw(requires = "\"resume\""))]
+    /// Count the time since the checkpoint was saved, such as while the
+    /// machine was down, as time spent retrying
+    pub count_downtime: bool,
+    #[structopt(long = "max-rate", parse(try_from_str = "parse_rate"))]
+    /// Start at most this many attempts a second, however short the interval,
+    /// so a command which fails at once can't spin (e.g. 2, or 0.1 for one
+    /// every ten seconds)
+    pub max_rate: Option<f64>,
+    #[structopt(long = "rate", parse(try_from_str = "parse_rate"))]
+    /// Let attempts through at this many a second on average, allowing a
+    /// quick --burst of them after a quiet spell (a token bucket, for rate
+    /// limits which allow bursts)
+    pub rate: Option<f64>,
+    #[structopt(
+        long = "burst",
+        parse(try_from_str = "parse_count"),
+        raw(requires = "\"rate\"")
+    )]
+    /// How many attempts --rate lets through in quick succession when none
+    /// have been made for a while [default: 1]
+    pub burst: Option<usize>,
+    #[structopt(long = "max-failures", parse(try_from_str = "parse_count"))]
+    /// Give up once the command has failed this many times in all. Successes
+    /// don't reset the count, even while confirming --consecutive-successes.
+    pub max_failures: Option<usize>,
+    #[structopt(long = "max-consecutive-failures", parse(try_from_str = "parse_count"))]
+    /// Give up once the command has failed this many times in a row, so that
+    /// a failure now and then is put up with, e.g. with --watch, but an
+    /// outage isn't
+    pub max_consecutive_failures: Option<usize>,
+    #[structopt(long = "grace")]
+    /// Don't count attempts which finish within this long of starting towards
+    /// --retries or --max-failures (e.g. 30, 500ms, 5m)
+    pub grace: Option<Seconds>,
+    #[structopt(
+        long = "consecutive-successes",
+        default_value = "1",
+        parse(try_from_str = "parse_count")
+    )]
+    /// Number of successes in a row required before stopping
+    pub consecutive_successes: usize,
+    #[structopt(long = "stability-window")]
+    /// Once the command succeeds, carry on running it, and only stop once it
+    /// has kept succeeding for this long (e.g. 30, 500ms, 5m); a failure
+    /// starts the window again from the next success
+    pub stability_window: Option<Seconds>,
+    #[structopt(long = "watch")]
+    /// Keep running the command after it succeeds, only stopping when it
+    /// gives up, with --timeout, --retries and --max-failures measured from
+    /// the last success. --on-success runs each time the command recovers.
+    pub watch: bool,
+    #[structopt(long = "reset-backoff-after", parse(try_from_str = "parse_count"))]
+    /// Start the backoff again from the first interval after this many
+    /// successes in a row, while confirming --consecutive-successes
+    pub reset_backoff_after: Option<usize>,
+    #[structopt(long = "adaptive-poll", raw(requires = "\"max_interval\""))]
+    /// Wait longer while the command's output stays the same from one
+    /// failed attempt to the next, up to --max-interval, and go back to the
+    /// first interval as soon as it changes. The waits grow by the
+    /// backoff base each time, unless another backoff is chosen.
+    pub adaptive_poll: bool,
+    #[structopt(long = "success-code", raw(number_of_values = "1"))]
+    /// Exit code which counts as success (may be repeated; defaults to 0)
+    pub success_codes: Vec<i32>,
+    #[structopt(long = "stable-for", parse(try_from_str = "parse_count"))]
+    /// Succeed once the command's output has been the same for this many
+    /// attempts in a row, whatever its exit code. This takes the place of
+    /// --check, the output patterns and the success codes, but
+    /// --until-file-exists, --until-tcp and the abort codes still apply.
+    pub stable_for: Option<usize>,
+    #[structopt(long = "check")]
+    /// Shell command run after each attempt which decides whether it
+    /// succeeded, by exiting with 0, in place of the command's exit code and
+    /// any output patterns; --abort-code and the --until conditions still
+    /// apply. RETRY_ITERATION and RETRY_EXIT_CODE hold the number and exit
+    /// code of the attempt.
+    pub check: Option<String>,
+    #[structopt(long = "until-stdout-matches", parse(try_from_str = "Regex::new"))]
+    /// Succeed when the command's stdout matches this regex, regardless of
+    /// its exit code
+    pub until_stdout_matches: Option<Regex>,
+    #[structopt(long = "until-stderr-matches", parse(try_from_str = "Regex::new"))]
+    /// Succeed when the command's stderr matches this regex, regardless of
+    /// its exit code
+    pub until_stderr_matches: Option<Regex>,
+    #[structopt(long = "until-stdout-not-matches", parse(try_from_str = "Regex::new"))]
+    /// Succeed when the command's stdout doesn't match this regex, regardless
+    /// of its exit code. With other output patterns, all of them have to be
+    /// satisfied, and --require-both also requires a successful exit code.
+    pub until_stdout_not_matches: Option<Regex>,
+    #[structopt(
+        long = "until-line",
+        parse(try_from_str = "Regex::new"),
+        raw(conflicts_with = "\"require_both\"")
+    )]
+    /// Succeed as soon as the command prints a line to stdout matching this
+    /// regex, stopping it then rather than waiting for it to exit, e.g. for a
+    /// server which logs when it is ready and then keeps running. Stopping
+    /// it is as for --attempt-timeout, and counts like an output pattern.
+    pub until_line: Option<Regex>,
+    #[structopt(
+        long = "until-stderr-empty",
+        raw(conflicts_with = "\"until_stderr_nonempty\"")
+    )]
+    /// Succeed when the command writes nothing to stderr, regardless of its
+    /// exit code, like an output pattern. Stderr holding only whitespace
+    /// counts as empty, unless --keep-stderr-whitespace is given.
+    pub until_stderr_empty: bool,
+    #[structopt(long = "until-stderr-nonempty")]
+    /// Succeed when the command writes something other than whitespace to
+    /// stderr, regardless of its exit code, like an output pattern
+    pub until_stderr_nonempty: bool,
+    #[structopt(long = "keep-stderr-whitespace")]
+    /// Count stderr holding only whitespace as written to, for
+    /// --until-stderr-empty and --until-stderr-nonempty
+    pub keep_stderr_whitespace: bool,
+    #[structopt(long = "until-file-exists", parse(from_os_str))]
+    /// Succeed only once this path exists, as well as the command succeeding;
+    /// the command may then be left out
+    pub until_file_exists: Option<PathBuf>,
+    #[structopt(long = "success-sentinel", parse(from_os_str))]
+    /// Stop retrying and exit successfully, whatever the command is doing,
+    /// if this path exists before an attempt, so that something else can
+    /// call off the retrying. The file is removed, unless --keep-sentinel is
+    /// given, so that it doesn't stop the next run too. --on-success isn't
+    /// run.
+    pub success_sentinel: Option<PathBuf>,
+    #[structopt(long = "keep-sentinel", raw(requires = "\"success_sentinel\""))]
+    /// Leave the --success-sentinel file in place once it has been seen
+    pub keep_sentinel: bool,
+    #[structopt(long = "until-tcp")]
+    /// Succeed only once host:port accepts a TCP connection, as well as the
+    /// command succeeding; the command may then be left out. Connecting
+    /// times out after --attempt-timeout, or a second.
+    pub until_tcp: Option<String>,
+    #[structopt(long = "until-http")]
+    /// Succeed only once a GET of this URL gets an expected response, as well
+    /// as the command succeeding; the command may then be left out
+    pub until_http: Option<String>,
+    #[structopt(long = "expect-status", raw(number_of_values = "1"))]
+    /// HTTP status which counts as ready with --until-http (may be repeated;
+    /// defaults to any 2xx)
+    pub expect_status: Vec<u16>,
+    #[structopt(long = "expect-body", parse(try_from_str = "Regex::new"))]
+    /// With --until-http, also require the response body to match this regex
+    pub expect_body: Option<Regex>,
+    #[structopt(long = "require-both")]
+    /// With an output pattern, also require a successful exit code
+    pub require_both: bool,
+    #[structopt(
+        long = "match-mode",
+        default_value = "all",
+        raw(possible_values = "&[\"all\", \"any\"]")
+    )]
+    /// Whether all of the conditions for success have to hold, or any one:
+    /// the exit code (unless replaced by --check, --stable-for or an output
+    /// pattern without --require-both), --check, --stable-for, each output
+    /// pattern, --until-line, --until-stderr-empty or --until-stderr-nonempty,
+    /// --until-file-exists, --until-tcp and --until-http
+    pub match_mode: MatchMode,
+    #[structopt(long = "abort-code", raw(number_of_values = "1"))]
+    /// Exit code which stops retrying immediately (may be repeated)
+    pub abort_codes: Vec<i32>,
+    #[structopt(
+        long = "exit-code-map",
+        parse(try_from_str = "parse_exit_class"),
+        raw(use_delimiter = "true", require_delimiter = "true")
+    )]
+    /// What exit codes mean, as comma separated CODE=success, CODE=retry or
+    /// CODE=abort (e.g. 0=success,2=retry,127=abort), alongside
+    /// --success-code and --abort-code. Other codes are retried.
+    pub exit_code_map: Vec<(i32, ExitClass)>,
+    #[structopt(long = "signal-is-failure", raw(overrides_with = "\"signal_aborts\""))]
+    /// Retry when the command is killed by a signal (the default)
+    pub signal_is_failure: bool,
+    #[structopt(long = "signal-aborts", raw(overrides_with = "\"signal_is_failure\""))]
+    /// Stop retrying when the command is killed by a signal, e.g. by the OOM
+    /// killer
+    pub signal_aborts: bool,
+    #[structopt(long = "on-first-failure")]
+    /// Shell command to run after the first failed attempt only (or the first
+    /// since --reset-backoff-after last applied); RETRY_ITERATION and
+    /// RETRY_EXIT_CODE hold the number and exit code of the attempt
+    pub on_first_failure: Option<String>,
+    #[structopt(long = "on-retry")]
+    /// Shell command to run after each failed attempt, before waiting to
+    /// retry; RETRY_ITERATION holds the number of the attempt which failed
+    pub on_retry: Option<String>,
+    #[structopt(long = "on-timeout")]
+    /// Shell command to run when retrying gives up because --timeout or
+    /// --budget ran out, e.g. to gather diagnostics; RETRY_ITERATION holds the
+    /// number of attempts made and RETRY_ELAPSED_MS how long retrying took
+    pub on_timeout: Option<String>,
+    #[structopt(long = "on-success")]
+    /// Shell command to run once the command succeeds; RETRY_ITERATION and
+    /// RETRY_EXIT_CODE hold the number and exit code of the successful attempt
+    pub on_success: Option<String>,
+    #[structopt(long = "on-success-required")]
+    /// Fail if the on-success hook fails
+    pub on_success_required: bool,
+    #[structopt(long = "invert")]
+    /// Retry until the command fails, i.e. exits with a code other than the
+    /// success codes (or is killed by a signal)
+    pub invert: bool,
+    #[structopt(
+        long = "backoff",
+        default_value = "constant",
+        raw(
+            possible_values = "&[\"constant\", \"linear\", \"exponential\", \"fibonacci\", \"decorrelated-jitter\"]"
+        )
+    )]
+    /// How the interval grows between attempts: constant waits the interval
+    /// every time, linear waits 1, 2, 3... intervals, exponential multiplies
+    /// the wait by the backoff base each time, fibonacci waits 1, 1, 2, 3,
+    /// 5... intervals, and decorrelated-jitter waits a random time between
+    /// the interval and three times the last wait
+    pub backoff: BackoffStrategy,
+    #[structopt(long = "backoff-base", default_value = "2.0")]
+    /// Growth factor for exponential backoff
+    pub backoff_base: f64,
+}
+
+impl Default for RetryOptions {
+    /// The same options as running retry without any flags
+    fn default() -> Self {
+        RetryOptions::from_iter(iter::once("retry"))
+    }
+}
+
+impl RetryOptions {
+    /// The meaning of every exit code which has been given one, failing if
+    /// any has been given two
+    pub fn exit_classes(&self) -> Result<BTreeMap<i32, ExitClass>> {
+        let given = self
+            .success_codes
+            .iter()
+            .map(|&rc| (rc, ExitClass::Success))
+            .chain(self.abort_codes.iter().map(|&rc| (rc, ExitClass::Abort)))
+            .chain(self.exit_code_map.iter().cloned());
+        let mut classes = BTreeMap::new();
+        for (rc, class) in given {
+            match classes.insert(rc, class) {
+                Some(previous) if previous != class => {
+                    bail!(
+                        "Exit code {} can't mean both {} and {}",
+                        rc,
+                        previous,
+                        class
+                    )
+                }
+                _ => (),
+            }
+        }
+        // Without any success codes, 0 means success, unless it has been
+        // given another meaning
+        if !classes.values().any(|&class| class == ExitClass::Success) {
+            classes.entry(0).or_insert(ExitClass::Success);
+        }
+        Ok(classes)
+    }
+
+    fn codes(&self, wanted: ExitClass) -> Vec<i32> {
+        self.exit_classes()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|&(_, class)| class == wanted)
+            .map(|(rc, _)| rc)
+            .collect()
+    }
+
+    /// The most times to run the command, from --retries or
+    /// --maximum-iterations
+    pub fn max_attempts(&self) -> Option<usize> {
+        match self.retries {
+            Some(retries) => Some(retries.saturating_add(1)),
+            None => match self.total_iterations_include_first {
+                Some(false) => self.maximum_iterations.map(|m| m.saturating_add(1)),
+                _ => self.maximum_iterations,
+            },
+        }
+    }
+
+    /// Warn about the options which are ignored here, once before retrying
+    /// rather than every time the command is run
+    pub fn warn_unsupported(&self) {
+        #[cfg(not(target_os = "linux"))]
+        {
+            if let Some(bytes) = self.max_memory {
+                eprintln!(
+                    "warning: ignoring --max-memory {}, which is only supported on Linux",
+                    bytes
+                );
+            }
+        }
+        #[cfg(windows)]
+        {
+            if let Some(n) = self.nice.filter(|&n| n < 0) {
+                eprintln!(
+                    "warning: ignoring --nice {}, as priority can't be raised here",
+                    n
+                );
+            }
+        }
+        #[cfg(not(any(unix, windows)))]
+        {
+            if let Some(n) = self.nice {
+                eprintln!(
+                    "warning: ignoring --nice {}, as priority can't be set here",
+                    n
+                );
+            }
+        }
+    }
+
+    pub fn success_codes(&self) -> Vec<i32> {
+        self.codes(ExitClass::Success)
+    }
+
+    pub fn abort_codes(&self) -> Vec<i32> {
+        self.codes(ExitClass::Abort)
+    }
+
+    /// The backoff chosen by --backoff, --multiplier or --schedule
+    fn backoff(&self, seed: u64) -> Box<dyn Backoff> {
+        if !self.schedule.is_empty() {
+            return Box::new(Schedule {
+                waits: self.schedule.iter().map(|s| s.0).collect(),
+            });
+        }
+        let interval = self.initial_interval.or(self.interval).map_or(0.0, |i| i.0);
+        match self.multiplier {
+            // A multiplier is exponential backoff by another name
+            Some(multiplier) => Box::new(Exponential {
+                interval,
+                base: multiplier,
+            }),
+            // Adaptive polling needs waits which grow
+            None if self.adaptive_poll && self.backoff == BackoffStrategy::Constant => {
+                Box::new(Exponential {
+                    interval,
+                    base: self.backoff_base,
+                })
+            }
+            None => self.backoff.backoff(
+                interval,
+                self.backoff_base,
+                self.max_interval.map(|m| m.0),
+                seed,
+            ),
+        }
+    }
+
+    pub fn build_loop_manager(&self) -> LoopManager {
+        let clock = MonotonicClock;
+        let now = clock.now();
+        // Chosen at random if need be, so that it can still be reported
+        let seed = self.seed.unwrap_or_else(rand::random);
+        let mut loop_manager = LoopManager {
+            clock: Arc::new(clock),
+            start_of_day: now,
+            carried_over: Duration::from_secs(0),
+            timeout: self.timeout.map(|t| t.0),
+            budget: self.budget.map(|b| b.0),
+            deadline: self.deadline,
+            // The schedule starts once the initial delay is over
+            next_due: self.delay.map(Seconds::duration).unwrap_or_default(),
+            backoff: self.backoff(seed),
+            first_interval: self.first_interval.map(|f| f.0),
+            hinted_interval: None,
+            max_interval: self.max_interval.map(|m| m.0),
+            steady: false,
+            jitter: self.jitter,
+            jitter_mode: self.jitter_mode,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            maximum_iterations: self.max_attempts(),
+            max_failures: self.max_failures,
+            max_consecutive_failures: self.max_consecutive_failures,
+            grace: self.grace.map(|g| g.0),
+            iteration: 0,
+            backoff_iteration: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            limits_from: Duration::from_secs(0),
+            limits_iteration: 0,
+            uncounted: 0,
+            limits_restarted: false,
+            last_exit_code: None,
+            last_http_status: None,
+            last_output: None,
+            tail: self.tail_on_failure.map(Tail::new),
+            report_interval: self.report_interval.map(|r| r.0),
+            last_report: now,
+            min_gap: self
+                .max_rate
+                .map(|rate| Duration::from_secs_f64(1.0 / rate)),
+            last_start: None,
+            bucket: self
+                .rate
+                .map(|rate| TokenBucket::new(rate, self.burst.unwrap_or(1))),
+            stabilising: false,
+        };
+        loop_manager.start_at(self.start_iteration);
+        loop_manager
+    }
+}
+
+/// What an exit code means, for --exit-code-map
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitClass {
+    Success,
+    Retry,
+    Abort,
+}
+
+impl FromStr for ExitClass {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "success" => Ok(ExitClass::Success),
+            "retry" => Ok(ExitClass::Retry),
+            "abort" => Ok(ExitClass::Abort),
+            _ => bail!(
+                "Unknown exit code meaning: {} (use success, retry or abort)",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ExitClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ExitClass::Success => "success",
+            ExitClass::Retry => "retry",
+            ExitClass::Abort => "abort",
+        })
+    }
+}
+
+/// How the conditions for success combine, for --match-mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchMode {
+    /// Every condition has to hold
+    All,
+    /// Any one condition is enough
+    Any,
+}
+
+impl FromStr for MatchMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => Ok(MatchMode::All),
+            "any" => Ok(MatchMode::Any),
+            _ => bail!("Unknown match mode: {} (use all or any)", s),
+        }
+    }
+}
+
+/// A command line duration, stored in seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Seconds(pub f64);
+
+impl Seconds {
+    pub fn duration(self) -> Duration {
+        Duration::from_millis(milliseconds(self.0))
+    }
+}
+
+impl FromStr for Seconds {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Seconds(parse_duration(s)?))
+    }
+}
+
+/// Parse a duration such as `500ms`, `30s`, `5m` or `2h` into seconds. A
+/// bare number is taken to be in seconds.
+pub fn parse_duration(s: &str) -> Result<f64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let scale = match unit {
+        "" | "s" => 1.0,
+        "ms" => 0.001,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => bail!(
+            "Unknown duration unit '{}' in '{}' (use ms, s, m or h)",
+            unit,
+            s
+        ),
+    };
+    let value: f64 = number
+        .parse()
+        .map_err(|_| format_err!("Invalid duration: '{}'", s))?;
+    Ok(value * scale)
+}
+
+pub fn parse_deadline(s: &str) -> Result<SystemTime> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.into());
+    }
+
+    let clock = NaiveTime::parse_from_str(s, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M:%S"))
+        .map_err(|_| format_err!("Expected an RFC 3339 time or HH:MM, got {}", s))?;
+    let now = Local::now();
+    let mut day = now.date_naive();
+    if day.and_time(clock) <= now.naive_local() {
+        day += ChronoDuration::days(1);
+    }
+    match Local.from_local_datetime(&day.and_time(clock)).earliest() {
+        Some(time) => Ok(time.into()),
+        None => bail!("{} does not exist in the local timezone on {}", s, day),
+    }
+}
+
+pub fn parse_directory(s: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(s);
+    if !path.is_dir() {
+        bail!("{} is not a directory", s);
+    }
+    Ok(path)
+}
+
+pub fn parse_env_var(s: &str) -> Result<(String, String)> {
+    match s.find('=') {
+        Some(i) if i > 0 => Ok((s[..i].to_string(), s[i + 1..].to_string())),
+        _ => bail!("Expected KEY=VALUE, got {}", s),
+    }
+}
+
+/// The variables in a .env file, which has a KEY=VALUE on each line, with
+/// blank lines and lines starting with # ignored. KEY may be preceded by
+/// `export`, and VALUE may be in single quotes, taken as written, or double
+/// quotes, in which \n, \t, \", \\ and \$ are escapes. An unquoted VALUE
+/// ends at a # preceded by a space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvFile {
+    pub vars: Vec<(String, String)>,
+}
+
+impl FromStr for EnvFile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let text = fs::read_to_string(s).map_err(|e| format_err!("Could not read {}: {}", s, e))?;
+        let vars = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+            .filter(|&(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(n, line)| {
+                env_file_line(line).map_err(|e| format_err!("{} line {}: {}", s, n, e))
+            })
+            .collect::<Result<_>>()?;
+        Ok(EnvFile { vars })
+    }
+}
+
+fn env_file_line(line: &str) -> Result<(String, String)> {
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = match line.find('=') {
+        Some(i) => (line[..i].trim(), line[i + 1..].trim_start()),
+        None => bail!("Expected KEY=VALUE, got {}", line),
+    };
+    let word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    if key.is_empty() || key.starts_with(|c: char| c.is_ascii_digit()) || !key.chars().all(word) {
+        bail!("Invalid variable name {:?}", key);
+    }
+    let (value, rest) = if let Some(quoted) = value.strip_prefix('\'') {
+        match quoted.find('\'') {
+            Some(end) => (quoted[..end].to_string(), &quoted[end + 1..]),
+            None => bail!("Missing closing ' in {}", line),
+        }
+    } else if let Some(quoted) = value.strip_prefix('"') {
+        let mut unquoted = String::new();
+        let mut chars = quoted.char_indices();
+        let end = loop {
+            match chars.next() {
+                Some((i, '"')) => break i,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => unquoted.push('\n'),
+                    Some((_, 't')) => unquoted.push('\t'),
+                    Some((_, c)) if "\"\\$".contains(c) => unquoted.push(c),
+                    Some((_, c)) => {
+                        unquoted.push('\\');
+                        unquoted.push(c);
+                    }
+                    None => bail!("Missing closing \" in {}", line),
+                },
+                Some((_, c)) => unquoted.push(c),
+                None => bail!("Missing closing \" in {}", line),
+            }
+        };
+        (unquoted, &quoted[end + 1..])
+    } else {
+        let end = value.find(" #").unwrap_or(value.len());
+        (value[..end].trim_end().to_string(), "")
+    };
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        bail!("Unexpected {} after the closing quote", rest);
+    }
+    Ok((key.to_string(), value))
+}
+
+pub fn parse_code_interval(s: &str) -> Result<(i32, Seconds)> {
+    match s.find('=') {
+        Some(i) => Ok((s[..i].trim().parse()?, s[i + 1..].trim().parse()?)),
+        None => bail!("Expected CODE=WAIT, got {}", s),
+    }
+}
+
+pub fn parse_exit_class(s: &str) -> Result<(i32, ExitClass)> {
+    match s.find('=') {
+        Some(i) => Ok((s[..i].trim().parse()?, s[i + 1..].trim().parse()?)),
+        None => bail!("Expected CODE=MEANING, got {}", s),
+    }
+}
+
+pub fn parse_rate(s: &str) -> Result<f64> {
+    let rate: f64 = s.parse()?;
+    if !(rate > 0.0 && rate.is_finite()) {
+        bail!("Expected a positive number of attempts a second, got {}", s);
+    }
+    // The time between attempts has to be one we can wait for
+    if Duration::try_from_secs_f64(1.0 / rate).is_err() {
+        bail!("{} attempts a second is too slow a rate", s);
+    }
+    Ok(rate)
+}
+
+pub fn parse_count(s: &str) -> Result<usize> {
+    match s.parse()? {
+        0 => bail!("Expected a number of at least 1, got {}", s),
+        n => Ok(n),
+    }
+}
+
+/// A number of bytes, which may have a K, M or G suffix for kibibytes,
+/// mebibytes or gibibytes
+pub fn parse_size(s: &str) -> Result<u64> {
+    let (number, scale) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let size = number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .filter(|&n| n > 0);
+    match size {
+        Some(size) => Ok(size),
+        None => bail!("Expected a size in bytes, such as 500M, got {}", s),
+    }
+}
+
+pub fn parse_fraction(s: &str) -> Result<f64> {
+    let fraction: f64 = s.parse()?;
+    if !(0.0..=1.0).contains(&fraction) {
+        bail!("Expected a fraction between 0.0 and 1.0, got {}", s);
+    }
+    Ok(fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> RetryOptions {
+        RetryOptions::from_iter(iter::once("retry").chain(args.iter().cloned()))
+    }
+
+    #[test]
+    fn maximum_iterations_can_count_only_the_retries() {
+        for &(m, include_first, attempts) in &[
+            ("1", "true", 1),
+            ("2", "true", 2),
+            ("3", "true", 3),
+            ("1", "false", 2),
+            ("2", "false", 3),
+            ("3", "false", 4),
+        ] {
+            let args = ["-m", m, "--total-iterations-include-first", include_first];
+            assert_eq!(
+                options(&args).max_attempts(),
+                Some(attempts),
+                "-m {} counting the first: {}",
+                m,
+                include_first
+            );
+        }
+    }
+
+    #[test]
+    fn maximum_iterations_counts_the_first_by_default() {
+        assert_eq!(options(&["-m", "2"]).max_attempts(), Some(2));
+        assert_eq!(options(&["--retries", "2"]).max_attempts(), Some(3));
+    }
+
+    #[test]
+    fn parse_rate_takes_positive_rates() {
+        assert_eq!(parse_rate("2").unwrap(), 2.0);
+        assert_eq!(parse_rate("0.1").unwrap(), 0.1);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("inf").is_err());
+    }
+
+    #[test]
+    fn parse_rate_rejects_rates_too_slow_to_wait_for() {
+        assert!(parse_rate("1e-30").is_err());
+        assert!(parse_rate("1e-18").is_ok());
+    }
+
+    #[test]
+    fn max_capture_takes_a_size() {
+        assert_eq!(options(&[]).max_capture, 1 << 20);
+        assert_eq!(options(&["--max-capture", "64K"]).max_capture, 64 << 10);
+        assert!(RetryOptions::from_iter_safe(&["retry", "--max-capture", "0"]).is_err());
+    }
+}
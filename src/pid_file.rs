@@ -0,0 +1,31 @@
+use quicli::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A file holding our PID, which is removed again when this is dropped
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> Result<PidFile> {
+        fs::write(path, format!("{}\n", process::id()))
+            .map_err(|e| format_err!("Could not write PID file {}: {}", path.display(), e))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!(
+                "warning: could not remove PID file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use quicli::prelude::*;
+use serde_json;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::time::Duration;
+
+use attempt_log::append_line;
+
+/// An attempt as it actually went, including the wait which followed it
+#[derive(Debug, Serialize)]
+struct Entry {
+    attempt: usize,
+    /// Time from the start of retrying to the start of the attempt
+    started_ms: u64,
+    duration_ms: u64,
+    exit_code: Option<i32>,
+    /// The wait before the next attempt, including any jitter, or none after
+    /// the last attempt
+    sleep_ms: Option<u64>,
+    /// What the random waits were drawn with, to repeat them with --seed
+    seed: u64,
+}
+
+/// A record of the schedule retrying followed, as a line of JSON per
+/// attempt. Each line is written once the wait after the attempt is known,
+/// or when this is dropped for the last attempt.
+#[derive(Debug)]
+pub(crate) struct PlanFile {
+    file: File,
+    pending: Option<Entry>,
+}
+
+impl PlanFile {
+    /// Open `path` for appending, creating it if need be
+    pub(crate) fn open(path: &Path) -> Result<PlanFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format_err!("Could not open plan file {}: {}", path.display(), e))?;
+        Ok(PlanFile {
+            file,
+            pending: None,
+        })
+    }
+
+    /// Note attempt number `attempt`, which started `started` into retrying
+    pub(crate) fn attempt(
+        &mut self,
+        attempt: usize,
+        started: Duration,
+        duration: Duration,
+        code: Option<i32>,
+        seed: u64,
+    ) -> Result<()> {
+        self.write()?;
+        self.pending = Some(Entry {
+            attempt,
+            started_ms: started.as_millis() as u64,
+            duration_ms: duration.as_millis() as u64,
+            exit_code: code,
+            sleep_ms: None,
+            seed,
+        });
+        Ok(())
+    }
+
+    /// Write out the last attempt, followed by a wait of `sleep`
+    pub(crate) fn sleep(&mut self, sleep: Duration) -> Result<()> {
+        if let Some(ref mut entry) = self.pending {
+            entry.sleep_ms = Some(sleep.as_millis() as u64);
+        }
+        self.write()
+    }
+
+    fn write(&mut self) -> Result<()> {
+        if let Some(entry) = self.pending.take() {
+            append_line(&mut self.file, &(serde_json::to_string(&entry)? + "\n"))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PlanFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.write() {
+            eprintln!("warning: could not write to plan file: {}", e);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use quicli::prelude::*;
+#[cfg(unix)]
+use std::cell::Cell;
+use std::io;
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus};
+
+#[cfg(unix)]
+use signals;
+
+/// Have `command` start in a process group of its own, so that anything it
+/// starts can be stopped along with it, returning whether the group is given
+/// the terminal. That is when we are in the foreground of one, so that the
+/// command can still read from it, and so that Ctrl-C and Ctrl-Z go to it.
+#[cfg(unix)]
+pub fn isolate(command: &mut Command) -> bool {
+    let foreground = in_foreground();
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Done here as well as once the command has started, so that it
+            // can't get to the terminal first
+            if foreground {
+                give_terminal(libc::getpgrp());
+            }
+            Ok(())
+        });
+    }
+    foreground
+}
+
+/// Process groups are only made on Unix; on Windows, the child is put in a
+/// job once it has started
+#[cfg(not(unix))]
+pub fn isolate(_command: &mut Command) -> bool {
+    false
+}
+
+/// Whether our process group is the one in the foreground of a terminal
+#[cfg(unix)]
+fn in_foreground() -> bool {
+    unsafe {
+        libc::isatty(libc::STDIN_FILENO) == 1
+            && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp()
+    }
+}
+
+/// Put the process group `group` in the foreground of our terminal
+#[cfg(unix)]
+unsafe fn give_terminal(group: libc::pid_t) {
+    // Which raises SIGTTOU from the background, unless it is ignored
+    let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+    libc::tcsetpgrp(libc::STDIN_FILENO, group);
+    libc::signal(libc::SIGTTOU, previous);
+}
+
+/// The processes an attempt started, which are stopped together
+pub struct ProcessGroup {
+    #[cfg(unix)]
+    pid: libc::pid_t,
+    /// Whether we have a terminal, which the child may be stopped by
+    #[cfg(unix)]
+    tty: bool,
+    /// Whether the group has the terminal, which we take back afterwards
+    #[cfg(unix)]
+    foreground: Cell<bool>,
+    #[cfg(windows)]
+    job: Option<job::Job>,
+}
+
+#[cfg(unix)]
+impl ProcessGroup {
+    /// The group `child` started in, giving it the terminal if `foreground`
+    pub fn of(child: &Child, foreground: bool) -> ProcessGroup {
+        let pid = child.id() as libc::pid_t;
+        if foreground {
+            unsafe { give_terminal(pid) };
+        }
+        ProcessGroup {
+            pid,
+            tty: unsafe { libc::isatty(libc::STDIN_FILENO) } == 1,
+            foreground: Cell::new(foreground),
+        }
+    }
+
+    /// Send `signal` to `child`, and everything in its group if it leads one
+    pub fn signal(&self, child: &Child, signal: libc::c_int) {
+        let pid = child.id() as libc::pid_t;
+        // The child hasn't been reaped yet, so its pid can't have been reused
+        unsafe {
+            if libc::getpgid(pid) == pid {
+                libc::kill(-pid, signal);
+            } else {
+                libc::kill(pid, signal);
+            }
+        }
+    }
+
+    /// Kill `child`, and everything in its group if it leads one
+    pub fn kill(&self, child: &mut Child) -> io::Result<()> {
+        self.signal(child, libc::SIGKILL);
+        child.kill()
+    }
+
+    /// Stop too if the child has been stopped by the terminal, as with
+    /// Ctrl-Z, or by reading from it in the background, so that the shell
+    /// can carry on as if the child was part of our job. The group is carried
+    /// on when we are, with the terminal if we have it.
+    pub fn follow_stop(&self) {
+        if !self.tty {
+            return;
+        }
+        unsafe {
+            let mut info: libc::siginfo_t = mem::zeroed();
+            // Left waitable, so that this only looks
+            let flags = libc::WSTOPPED | libc::WNOHANG | libc::WNOWAIT;
+            if libc::waitid(libc::P_PID, self.pid as libc::id_t, &mut info, flags) != 0
+                || info.si_pid() == 0
+            {
+                return;
+            }
+            debug!("Attempt was stopped by the terminal, stopping too");
+            self.release();
+            libc::raise(libc::SIGTSTP);
+            let foreground = in_foreground();
+            if foreground {
+                give_terminal(self.pid);
+            }
+            self.foreground.set(foreground);
+            libc::kill(-self.pid, libc::SIGCONT);
+        }
+    }
+
+    /// Take the terminal back, if the group has it
+    fn release(&self) {
+        if self.foreground.replace(false) {
+            unsafe { give_terminal(libc::getpgrp()) };
+        }
+    }
+
+    /// Take the terminal back as soon as the child exits with `status`.
+    /// While the group has the terminal, Ctrl-C goes to it rather than to
+    /// us, so if the child was killed by it, or exited with 130 as shells and
+    /// programs which catch it do, we count as interrupted too.
+    pub fn exited(&self, status: ExitStatus) {
+        let interrupted =
+            status.signal() == Some(libc::SIGINT) || status.code() == Some(128 + libc::SIGINT);
+        if self.foreground.get() && interrupted {
+            signals::interrupt();
+        }
+        self.release();
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(windows)]
+impl ProcessGroup {
+    /// A job holding `child`, and so anything it starts from now on
+    pub fn of(child: &Child, _foreground: bool) -> ProcessGroup {
+        let job = job::Job::new().and_then(|job| job.assign(child).map(|()| job));
+        if let Err(ref e) = job {
+            debug!("Could not put attempt {} in a job: {}", child.id(), e);
+        }
+        ProcessGroup { job: job.ok() }
+    }
+
+    /// Kill `child`, and everything else in its job
+    pub fn kill(&self, child: &mut Child) -> io::Result<()> {
+        if let Some(ref job) = self.job {
+            let _ = job.terminate();
+        }
+        child.kill()
+    }
+
+    pub fn follow_stop(&self) {}
+
+    pub fn exited(&self, _status: ExitStatus) {}
+}
+
+#[cfg(not(any(unix, windows)))]
+impl ProcessGroup {
+    pub fn of(_child: &Child, _foreground: bool) -> ProcessGroup {
+        ProcessGroup {}
+    }
+
+    pub fn kill(&self, child: &mut Child) -> io::Result<()> {
+        child.kill()
+    }
+
+    pub fn follow_stop(&self) {}
+
+    pub fn exited(&self, _status: ExitStatus) {}
+}
+
+#[cfg(windows)]
+mod job {
+    use std::io;
+    use std::os::raw::c_void;
+    use std::os::windows::io::AsRawHandle;
+    use std::process::Child;
+    use std::ptr;
+
+    type Handle = *mut c_void;
+
+    extern "system" {
+        fn CreateJobObjectW(attributes: *mut c_void, name: *const u16) -> Handle;
+        fn AssignProcessToJobObject(job: Handle, process: Handle) -> i32;
+        fn TerminateJobObject(job: Handle, exit_code: u32) -> i32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    /// A Windows job object, which the processes in it can be killed through
+    pub struct Job(Handle);
+
+    impl Job {
+        pub fn new() -> io::Result<Job> {
+            let handle = unsafe { CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+            if handle.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Job(handle))
+        }
+
+        pub fn assign(&self, child: &Child) -> io::Result<()> {
+            if unsafe { AssignProcessToJobObject(self.0, child.as_raw_handle() as Handle) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        pub fn terminate(&self) -> io::Result<()> {
+            if unsafe { TerminateJobObject(self.0, 1) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            unsafe {
+                CloseHandle(self.0);
+            }
+        }
+    }
+}
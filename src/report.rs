@@ -0,0 +1,178 @@
+use quicli::prelude::*;
+use serde;
+use serde_json;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use ureq;
+
+use display::human_duration;
+use error::RetryError;
+use loop_manager::LoopManager;
+use options::RetryOptions;
+use syslog::{self, Priority};
+
+/// How long to wait for a webhook to respond
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A line of --json-logs, saying what happened and when
+#[derive(Debug, Serialize)]
+struct Event<'a, T: 'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    data: &'a T,
+}
+
+/// `data` as a line of JSON, as event `event`
+fn event_json<T: serde::Serialize>(event: &'static str, data: &T) -> Result<String> {
+    Ok(serde_json::to_string(&Event { event, data })?)
+}
+
+/// An attempt, as logged with --json-logs
+#[derive(Debug, Serialize)]
+pub(crate) struct AttemptEvent {
+    pub iteration: usize,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    pub elapsed_ms: u64,
+}
+
+impl AttemptEvent {
+    /// Write the event to stderr, and to syslog with --syslog
+    pub(crate) fn log(&self, options: &RetryOptions) -> Result<()> {
+        let json = event_json("attempt", self)?;
+        eprintln!("{}", json);
+        if options.syslog {
+            let priority = match self.exit_code {
+                Some(0) => Priority::Info,
+                _ => Priority::Error,
+            };
+            syslog::send(
+                &options.syslog_tag,
+                options.syslog_facility,
+                priority,
+                &json,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Machine readable summary of a run
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub command: Vec<String>,
+    pub iterations: usize,
+    pub elapsed_ms: u64,
+    pub exit_code: Option<i32>,
+    pub reason: &'static str,
+    /// What the random waits were drawn with, to repeat them with --seed
+    pub seed: u64,
+}
+
+impl Report {
+    pub fn new<T>(
+        command: &[String],
+        loop_manager: &LoopManager,
+        result: &Result<T>,
+    ) -> Result<Report> {
+        let reason = match *result {
+            Ok(_) => "success",
+            Err(ref e) => e
+                .downcast_ref::<RetryError>()
+                .map_or("error", RetryError::reason),
+        };
+        Ok(Report {
+            command: command.to_vec(),
+            iterations: loop_manager.iteration() + 1,
+            elapsed_ms: loop_manager.elapsed()?.as_millis() as u64,
+            exit_code: loop_manager.last_exit_code(),
+            reason,
+            seed: loop_manager.seed(),
+        })
+    }
+
+    /// `template` with {iterations}, {elapsed}, {elapsed_ms}, {exit_code} and
+    /// {reason} filled in from the report. Anything else in braces is left as
+    /// it is.
+    pub fn render(&self, template: &str) -> String {
+        let mut rendered = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let name = match rest[1..].find(['{', '}']) {
+                Some(end) if rest.as_bytes()[end + 1] == b'}' => &rest[1..=end],
+                _ => {
+                    rendered.push('{');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+            let value = match name {
+                "iterations" => Some(self.iterations.to_string()),
+                "elapsed" => Some(human_duration(Duration::from_millis(self.elapsed_ms))),
+                "elapsed_ms" => Some(self.elapsed_ms.to_string()),
+                "exit_code" => Some(self.exit_code.map(|rc| rc.to_string()).unwrap_or_default()),
+                "reason" => Some(self.reason.to_string()),
+                _ => None,
+            };
+            match value {
+                Some(value) => rendered.push_str(&value),
+                None => {
+                    eprintln!(
+                        "warning: leaving unknown placeholder {{{}}} in message",
+                        name
+                    );
+                    rendered.push_str(&rest[..name.len() + 2]);
+                }
+            }
+            rest = &rest[name.len() + 2..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+
+    /// Write the report as a line of JSON to `path`, or stdout
+    pub fn write(&self, path: Option<&Path>) -> Result<()> {
+        let json = serde_json::to_string(self)?;
+        match path {
+            Some(path) => fs::write(path, json + "\n")?,
+            None => println!("{}", json),
+        }
+        Ok(())
+    }
+
+    /// Write the report as the last line of --json-logs
+    pub fn log(&self) -> Result<()> {
+        eprintln!("{}", event_json("summary", self)?);
+        Ok(())
+    }
+
+    /// Send the report to syslog, as set up by `options`
+    pub fn syslog(&self, options: &RetryOptions) -> Result<()> {
+        let priority = if self.reason == "success" {
+            Priority::Info
+        } else {
+            Priority::Error
+        };
+        let json = event_json("summary", self)?;
+        syslog::send(
+            &options.syslog_tag,
+            options.syslog_facility,
+            priority,
+            &json,
+        );
+        Ok(())
+    }
+
+    /// POST the report as JSON to `url`
+    pub fn post(&self, url: &str) -> Result<()> {
+        let json = serde_json::to_string(self)?;
+        ureq::post(url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .set("Content-Type", "application/json")
+            .send_string(&json)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use ctrlc;
+use quicli::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a sleep checks whether it has been interrupted
+const SLEEP_GRANULARITY: Duration = Duration::from_millis(50);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Catch SIGINT and SIGTERM (Ctrl-C on Windows), so that retrying can stop
+/// cleanly rather than dying mid attempt
+pub fn handle_signals() -> Result<()> {
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst))?;
+    Ok(())
+}
+
+/// Have the kernel send us SIGTERM when our parent dies, so that we stop
+/// retrying, and kill any attempt in flight, rather than being orphaned
+#[cfg(target_os = "linux")]
+pub fn die_with_parent() -> Result<()> {
+    let parent = unsafe { libc::getppid() };
+    if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) } != 0 {
+        bail!(
+            "Could not ask to die with parent: {}",
+            ::std::io::Error::last_os_error()
+        );
+    }
+    // The parent may have died before it could be watched, leaving us
+    // adopted by another process
+    if unsafe { libc::getppid() } != parent {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+/// Parent death can only be watched for on Linux
+#[cfg(not(target_os = "linux"))]
+pub fn die_with_parent() -> Result<()> {
+    eprintln!("warning: ignoring --die-with-parent, which is only supported on Linux");
+    Ok(())
+}
+
+/// Stop retrying, as if we had been sent SIGINT
+pub(crate) fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether we have been asked to stop
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Sleep for `duration`, returning early if we are interrupted
+pub fn sleep(duration: Duration) -> Result<()> {
+    sleep_with(duration, |_| ())
+}
+
+/// Sleep for `duration`, returning early if we are interrupted, and calling
+/// `tick` with the time remaining every so often
+pub fn sleep_with<F>(duration: Duration, mut tick: F) -> Result<()>
+where
+    F: FnMut(Duration),
+{
+    // Measured against the monotonic clock, so that time spent in `tick`, or
+    // oversleeping, doesn't add up over a long sleep. A sleep too long for
+    // the clock to represent never ends, unless interrupted.
+    let deadline = Instant::now().checked_add(duration);
+    while !interrupted() {
+        let remaining = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => duration,
+        };
+        if remaining == Duration::from_secs(0) {
+            break;
+        }
+        tick(remaining);
+        thread::sleep(remaining.min(SLEEP_GRANULARITY));
+    }
+    Ok(())
+}
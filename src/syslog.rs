@@ -0,0 +1,86 @@
+use quicli::prelude::*;
+use std::str::FromStr;
+
+/// Where messages are filed by the system logger
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyslogFacility {
+    User,
+    Daemon,
+    /// One of local0 to local7
+    Local(u8),
+}
+
+impl FromStr for SyslogFacility {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "user" => Ok(SyslogFacility::User),
+            "daemon" => Ok(SyslogFacility::Daemon),
+            _ => match s.strip_prefix("local").map(str::parse) {
+                Some(Ok(n)) if n <= 7 => Ok(SyslogFacility::Local(n)),
+                _ => bail!(
+                    "Unknown syslog facility: {} (expected user, daemon or local0 to local7)",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+/// How much a message sent to the system logger matters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Priority {
+    Info,
+    Error,
+}
+
+#[cfg(unix)]
+impl SyslogFacility {
+    fn code(self) -> libc::c_int {
+        match self {
+            SyslogFacility::User => libc::LOG_USER,
+            SyslogFacility::Daemon => libc::LOG_DAEMON,
+            SyslogFacility::Local(n) => libc::LOG_LOCAL0 + (libc::c_int::from(n) << 3),
+        }
+    }
+}
+
+/// Send `message` to the system logger as from `tag`. Messages are dropped
+/// quietly if there is no logger to take them, as they are only a record.
+#[cfg(unix)]
+pub(crate) fn send(tag: &str, facility: SyslogFacility, priority: Priority, message: &str) {
+    use std::ffi::CString;
+    use std::sync::Once;
+
+    static OPEN: Once = Once::new();
+    // Without any NULs, the conversions can't fail
+    let c_string = |s: &str| CString::new(s.replace('\0', "")).unwrap_or_default();
+    OPEN.call_once(|| {
+        // openlog keeps the tag, so it has to live as long as we do
+        let tag = Box::leak(c_string(tag).into_boxed_c_str());
+        unsafe { libc::openlog(tag.as_ptr(), libc::LOG_PID, facility.code()) };
+    });
+    let priority = match priority {
+        Priority::Info => libc::LOG_INFO,
+        Priority::Error => libc::LOG_ERR,
+    };
+    let message = c_string(message);
+    unsafe {
+        libc::syslog(
+            priority,
+            b"%s\0".as_ptr() as *const libc::c_char,
+            message.as_ptr(),
+        )
+    };
+}
+
+/// There is no system logger outside Unix, so the message goes to stderr
+#[cfg(not(unix))]
+pub(crate) fn send(tag: &str, _facility: SyslogFacility, _priority: Priority, message: &str) {
+    use std::sync::Once;
+
+    static WARN: Once = Once::new();
+    WARN.call_once(|| eprintln!("warning: there is no syslog here, so --syslog goes to stderr"));
+    eprintln!("{}: {}", tag, message);
+}